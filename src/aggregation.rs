@@ -0,0 +1,276 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    state::ChangedUnitStatus,
+    status::{ActiveState, UnitStatus},
+};
+
+/// Holds changes of units for a configurable window before they are dispatched, so that cascading
+/// failures are reported together and repeated transitions of the same unit are merged into their net result.
+///
+/// The window starts with the first change and all changes that arrive until it ends are collected.
+/// If a file path is set, the collected changes are stored on disk, as they are already part of the state file
+/// and would be lost otherwise when the program stops within the window.
+pub struct Aggregation {
+    window: Duration,
+    started: Option<Instant>,
+    pending: Vec<ChangedUnitStatus>,
+    file_path: Option<PathBuf>,
+}
+
+impl Aggregation {
+    /// Creates a new aggregation with the given window.
+    /// A zero window dispatches all changes immediately.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: None,
+            pending: Vec::new(),
+            file_path: None,
+        }
+    }
+
+    /// Stores the collected changes at the given path.
+    /// Any changes that were previously stored at this path are loaded and a new window is started for them.
+    pub fn with_file_path(mut self, file_path: PathBuf) -> Self {
+        self.pending = fs::read(&file_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        if !self.pending.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        self.file_path = Some(file_path);
+        self
+    }
+
+    /// Returns the path of the file with the collected changes that belongs to the given state file path,
+    /// i.e. a file next to the state file.
+    pub fn file_path_for_state_file(state_file_path: &Path) -> PathBuf {
+        state_file_path.with_extension("aggregation.json")
+    }
+
+    /// Adds the changes to the window and starts the window, if it is not already running.
+    ///
+    /// A change of a unit that already has a pending change is merged: the new state is always the latest one,
+    /// but the old state is replaced by the failed state the unit recovered from, if any.
+    /// This way, a unit that failed and recovered within the window is reported as recovered,
+    /// while a unit that is failed at the end of the window is reported as failed.
+    /// A unit that recovered from a failure and failed again is reported as failed from the state it recovered to.
+    pub fn add(&mut self, changes: Vec<ChangedUnitStatus>, now: Instant) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.started.get_or_insert(now);
+        for change in changes {
+            let existing = self
+                .pending
+                .iter_mut()
+                .find(|pending| pending.new.name() == change.new.name());
+            match existing {
+                Some(existing) => {
+                    let recovered_from_failure = is_failed(change.old.as_ref());
+                    let failed_again =
+                        is_failed(existing.old.as_ref()) && is_failed(Some(&change.new));
+                    if recovered_from_failure || failed_again {
+                        existing.old = change.old;
+                    }
+                    existing.new = change.new;
+                }
+                None => self.pending.push(change),
+            }
+        }
+        self.save()
+    }
+
+    /// Returns all collected changes and resets the window, if the window has elapsed.
+    /// Otherwise, an empty list is returned.
+    /// Units that are back in their state from before the window are omitted.
+    pub fn take_if_elapsed(&mut self, now: Instant) -> Result<Vec<ChangedUnitStatus>> {
+        match self.started {
+            Some(started) if now.saturating_duration_since(started) >= self.window => self.take(),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns all collected changes and resets the window, even if the window has not elapsed yet,
+    /// e.g. when the program stops.
+    pub fn take(&mut self) -> Result<Vec<ChangedUnitStatus>> {
+        self.started = None;
+        let pending = std::mem::take(&mut self.pending);
+        self.save()?;
+        Ok(pending
+            .into_iter()
+            .filter(|change| change.old.as_ref() != Some(&change.new))
+            .collect())
+    }
+
+    /// Writes the collected changes to disk, if a file path is set.
+    fn save(&self) -> Result<()> {
+        let file_path = match &self.file_path {
+            Some(file_path) => file_path,
+            None => return Ok(()),
+        };
+        let serialized =
+            serde_json::to_string(&self.pending).context("could not serialize aggregation")?;
+        if let Some(dir_path) = file_path.parent() {
+            fs::create_dir_all(dir_path).context(format!(
+                "could not create directories for the aggregation file ({:?})",
+                dir_path
+            ))?;
+        }
+        fs::write(file_path, serialized).context(format!(
+            "could not write aggregation to file ({:?})",
+            file_path
+        ))
+    }
+}
+
+fn is_failed(status: Option<&UnitStatus>) -> bool {
+    status.is_some_and(|status| status.active_state() == &ActiveState::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{temp_file_path, unit_status};
+
+    fn change(name: &str, old: &str, new: &str) -> ChangedUnitStatus {
+        ChangedUnitStatus {
+            old: Some(unit_status(name, old)),
+            new: unit_status(name, new),
+        }
+    }
+
+    #[test]
+    fn zero_window_dispatches_immediately() {
+        let mut aggregation = Aggregation::new(Duration::ZERO);
+        let now = Instant::now();
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+        assert_eq!(
+            aggregation.take_if_elapsed(now).unwrap(),
+            vec![change("a", "active", "failed")]
+        );
+        assert_eq!(aggregation.take_if_elapsed(now).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn changes_are_held_until_window_elapsed() {
+        let mut aggregation = Aggregation::new(Duration::from_secs(30));
+        let now = Instant::now();
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+        aggregation
+            .add(
+                vec![change("b", "active", "failed")],
+                now + Duration::from_secs(10),
+            )
+            .unwrap();
+        assert_eq!(
+            aggregation
+                .take_if_elapsed(now + Duration::from_secs(29))
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            aggregation
+                .take_if_elapsed(now + Duration::from_secs(30))
+                .unwrap(),
+            vec![
+                change("a", "active", "failed"),
+                change("b", "active", "failed")
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_then_recovered_is_merged_into_recovery() {
+        let mut aggregation = Aggregation::new(Duration::from_secs(30));
+        let now = Instant::now();
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+        aggregation
+            .add(vec![change("a", "failed", "activating")], now)
+            .unwrap();
+        aggregation
+            .add(vec![change("a", "activating", "active")], now)
+            .unwrap();
+        assert_eq!(
+            aggregation
+                .take_if_elapsed(now + Duration::from_secs(30))
+                .unwrap(),
+            vec![change("a", "failed", "active")]
+        );
+    }
+
+    #[test]
+    fn repeated_failure_is_merged_into_failure() {
+        let mut aggregation = Aggregation::new(Duration::from_secs(30));
+        let now = Instant::now();
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+        aggregation
+            .add(vec![change("a", "failed", "active")], now)
+            .unwrap();
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+        assert_eq!(
+            aggregation
+                .take_if_elapsed(now + Duration::from_secs(30))
+                .unwrap(),
+            vec![change("a", "active", "failed")]
+        );
+    }
+
+    #[test]
+    fn unchanged_units_are_omitted() {
+        let mut aggregation = Aggregation::new(Duration::from_secs(30));
+        let now = Instant::now();
+        aggregation
+            .add(vec![change("a", "active", "deactivating")], now)
+            .unwrap();
+        aggregation
+            .add(vec![change("a", "deactivating", "active")], now)
+            .unwrap();
+        assert_eq!(aggregation.take().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn held_changes_are_stored() {
+        let path = temp_file_path();
+        let now = Instant::now();
+        let mut aggregation =
+            Aggregation::new(Duration::from_secs(30)).with_file_path(path.clone());
+        aggregation
+            .add(vec![change("a", "active", "failed")], now)
+            .unwrap();
+
+        let mut aggregation =
+            Aggregation::new(Duration::from_secs(30)).with_file_path(path.clone());
+        assert_eq!(aggregation.take_if_elapsed(now).unwrap(), vec![]);
+        assert_eq!(
+            aggregation.take().unwrap(),
+            vec![change("a", "active", "failed")]
+        );
+        let mut aggregation = Aggregation::new(Duration::from_secs(30)).with_file_path(path);
+        assert_eq!(aggregation.take().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    config::Config,
+    notifications::{self, NotificationProvider},
+};
+
+/// Prints a report about the configuration, which was already parsed and validated successfully at this point,
+/// and creates all notification providers.
+/// If `connectivity` is set, every notification provider is additionally checked for reachability.
+/// Returns an error, if any check failed.
+pub fn run(config: &Config, connectivity: bool) -> Result<()> {
+    println!("configuration: ok");
+    println!(
+        "  units: types {}, {} include and {} exclude patterns",
+        config.unit_filter.unit_types.join(", "),
+        config.unit_filter.include.len(),
+        config.unit_filter.exclude.len()
+    );
+    println!("  rules: {}", config.rules.0.len());
+    println!("  routes: {}", config.routes.0.len());
+
+    let notifications = match notifications::create_notifications(config) {
+        Ok(notifications) => notifications,
+        Err(error) => {
+            println!("notification providers: error: {:#}", error);
+            return Err(anyhow!("configuration check failed"));
+        }
+    };
+    let results = check_providers(&notifications, connectivity);
+    let mut failed = false;
+    for (name, result) in results {
+        match result {
+            Ok(()) if connectivity => println!("provider '{}': reachable", name),
+            Ok(()) => println!("provider '{}': ok", name),
+            Err(error) => {
+                failed = true;
+                println!("provider '{}': error: {:#}", name, error);
+            }
+        }
+    }
+    if failed {
+        return Err(anyhow!("configuration check failed"));
+    }
+    Ok(())
+}
+
+/// Returns the result of the check of every notification provider by its name.
+/// The connectivity of all providers is checked in parallel.
+fn check_providers(
+    notifications: &[Box<dyn NotificationProvider>],
+    connectivity: bool,
+) -> Vec<(String, Result<()>)> {
+    let thread_handles: Vec<_> = notifications
+        .iter()
+        .map(|provider| {
+            let check = provider.check_connectivity();
+            let handle = connectivity.then(|| std::thread::spawn(check));
+            (provider.name().to_string(), handle)
+        })
+        .collect();
+    thread_handles
+        .into_iter()
+        .map(|(name, handle)| {
+            let result = match handle {
+                Some(handle) => handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("the check panicked"))),
+                None => Ok(()),
+            };
+            (name, result)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::tests::MockupNotificationProvider;
+
+    #[test]
+    fn unreachable_providers_fail_the_check() {
+        let mut unreachable = MockupNotificationProvider::new();
+        unreachable.error = true;
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![
+            Box::new(MockupNotificationProvider::new()),
+            Box::new(unreachable),
+        ];
+
+        let results = check_providers(&notifications, true);
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_err());
+        // without the connectivity check, the providers are not contacted
+        assert!(check_providers(&notifications, false)
+            .iter()
+            .all(|(_, result)| result.is_ok()));
+    }
+}
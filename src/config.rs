@@ -4,20 +4,140 @@ SPDX-FileCopyrightText: 2021 localthomas
 SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
-use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config_file::{ConfigFile, ProviderSection, ProviderType},
+    control::ControlCommand,
+    filter::UnitFilter,
+    notifications::Severity,
+    pattern::Pattern,
+    quiet_hours::QuietHours,
+    routing::Routes,
+    rules::Rules,
+    secret::Secret,
+    state::unix_now,
+    test_notification::TestUnit,
+};
 
 /// Holds the static configuration for the program.
 /// Can be used to alter the behavior of the execution or to configure notification provider.
 pub struct Config {
-    pub discord_webhook_url: Option<String>,
+    pub providers: Vec<ProviderConfig>,
     pub state_file_path: String,
+    pub control_socket_path: String,
+    pub outbox_max_age: Duration,
+    pub unit_filter: UnitFilter,
+    pub rules: Rules,
+    pub routes: Routes,
+    pub aggregation_window: Duration,
+    pub flap_threshold: usize,
+    pub flap_window: Duration,
+    pub reminder_interval: Option<Duration>,
+    /// The interval in which systemd is polled for changes.
+    pub poll_interval: Duration,
+    /// The time after which a D-Bus call to systemd is abandoned.
+    pub dbus_timeout: Duration,
+    /// The time for which the connection to systemd is re-established after it was lost, before giving up.
+    pub reconnect_budget: Duration,
     pub about: bool,
     pub disable_start_notification: bool,
+    /// If set, the state of systemd is checked only once instead of running the daemon.
+    pub once: bool,
+    /// If set, this subcommand is executed instead of running the daemon.
+    pub command: Option<Subcommand>,
+}
+
+/// The name of the Discord provider that is configured by the command line and environment variables.
+const DISCORD_PROVIDER_NAME: &str = "discord";
+
+/// A named instance of a notification provider.
+pub struct ProviderConfig {
+    pub name: String,
+    pub settings: ProviderSettings,
+}
+
+/// The type of a notification provider with its type specific configuration.
+pub enum ProviderSettings {
+    Discord(DiscordConfig),
+}
+
+pub struct DiscordConfig {
+    pub webhook_url: Secret,
+    /// If set, the changes of multiple units are sent as digest instead of one message per unit.
+    pub batch: bool,
+    /// If set, the provider only receives escalations of failures after this delay.
+    pub escalate_after: Option<Duration>,
+    /// If set, less severe notifications are held during them.
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// A subcommand of the program that is executed once instead of running the daemon.
+pub enum Subcommand {
+    /// Send a command to the running daemon or apply it to the state directly, if no daemon is running.
+    Control(ControlCommand),
+    /// Validate the configuration and optionally check the connectivity of the notification providers.
+    CheckConfig { connectivity: bool },
+    /// Send notifications about a synthetic unit to all notification providers.
+    TestNotification(TestUnit),
+    /// Print the failed units, optionally queried from systemd instead of the state file.
+    Status { live: bool, json: bool },
+    /// Notify about the current state of a single unit, e.g. from an `OnFailure=` unit.
+    NotifyUnit { unit: String },
 }
 
 impl Config {
+    /// Returns the names of the settings that differ in the other configuration, but are only applied on start,
+    /// i.e. that can not be changed by reloading the configuration.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        [
+            (
+                "state-file-path",
+                self.state_file_path != other.state_file_path,
+            ),
+            (
+                "control-socket-path",
+                self.control_socket_path != other.control_socket_path,
+            ),
+            (
+                "outbox-max-age",
+                self.outbox_max_age != other.outbox_max_age,
+            ),
+            (
+                "aggregation-window",
+                self.aggregation_window != other.aggregation_window,
+            ),
+            (
+                "flap-threshold",
+                self.flap_threshold != other.flap_threshold,
+            ),
+            ("flap-window", self.flap_window != other.flap_window),
+            ("poll-interval", self.poll_interval != other.poll_interval),
+            ("dbus-timeout", self.dbus_timeout != other.dbus_timeout),
+            (
+                "reconnect-budget",
+                self.reconnect_budget != other.reconnect_budget,
+            ),
+        ]
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+
     /// Read command line arguments and flags, as well as environment variables to parse the configuration.
     pub fn new() -> Result<Self> {
+        const CONFIG: (&str, &str, &str) = (
+            "config",
+            "SYSTEMD_FAIL_NOTIFICATIONS_CONFIG",
+            "the path to a TOML configuration file; command line arguments and environment variables take precedence over its values",
+        );
         const ABOUT: (&str, char, &str) = (
             "about",
             'a',
@@ -27,62 +147,883 @@ impl Config {
             "disable-start-notification",
             "disables the initial notification about the application starting",
         );
+        const ONCE: (&str, &str) = (
+            "once",
+            "check the state of systemd only once, deliver the notifications and exit with exit code 2 if any unit is failed, e.g. for cron jobs",
+        );
         const DISCORD_WEBHOOK_URL: (&str, &str, &str) = (
             "discord-webhook-url",
             "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_WEBHOOK_URL",
             "the webhook-URL of the Discord webhook like 'https://discord.com/api/webhooks/<id>/<token>'",
         );
+        const DISCORD_WEBHOOK_URL_FILE: (&str, &str, &str) = (
+            "discord-webhook-url-file",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_WEBHOOK_URL_FILE",
+            "the path to a file that contains the webhook-URL of the Discord webhook; the systemd credential 'discord-webhook-url' is used if neither this nor the URL is set",
+        );
+        const DISCORD_BATCH: (&str, &str, &str) = (
+            "discord-batch",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_BATCH",
+            "if set, all unit changes of one poll are sent as a single Discord message with up to 10 embeds",
+        );
+        const DISCORD_ESCALATE_AFTER: (&str, &str, &str) = (
+            "discord-escalate-after",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_ESCALATE_AFTER",
+            "if set, the Discord webhook is an escalation tier and only notified about units that are still failed after this number of seconds; requires another notification provider without it",
+        );
+        const DISCORD_QUIET_HOURS: (&str, &str, &str) = (
+            "discord-quiet-hours",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_QUIET_HOURS",
+            "a schedule in local time like 'Mon-Fri 22:00-07:00; Sat,Sun 00:00-24:00' during which less severe notifications are held and sent as digest afterwards",
+        );
+        const DISCORD_QUIET_HOURS_BYPASS: (&str, &str, &str) = (
+            "discord-quiet-hours-bypass",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DISCORD_QUIET_HOURS_BYPASS",
+            "the minimum severity (info, warning or critical) of notifications that are sent during quiet hours",
+        );
         const STATE_FILE_PATH: (&str, &str, &str) = (
             "state-file-path",
             "SYSTEMD_FAIL_NOTIFICATIONS_STATE_FILE_PATH",
             "the path to a file were the state can be stored",
         );
-        use clap::{Arg, Command};
+        const CONTROL_SOCKET_PATH: (&str, &str, &str) = (
+            "control-socket-path",
+            "SYSTEMD_FAIL_NOTIFICATIONS_CONTROL_SOCKET_PATH",
+            "the path to the unix socket for commands to the running daemon (default: next to the state file)",
+        );
+        const ACKNOWLEDGE: (&str, &str, &str) = (
+            "acknowledge",
+            "ack",
+            "acknowledge the current failure of a unit, so that no more reminders or escalations are sent until it recovers",
+        );
+        const SILENCE: (&str, &str) = (
+            "silence",
+            "manage silences that suppress notifications about matching units, e.g. during maintenance",
+        );
+        const CHECK_CONFIG: (&str, &str) = (
+            "check-config",
+            "validate the configuration, create all notification providers and exit with a non-zero exit code on errors",
+        );
+        const TEST_NOTIFICATION: (&str, &str) = (
+            "test-notification",
+            "send a notification about a synthetic unit, an error and a start notification to all notification providers",
+        );
+        const STATUS: (&str, &str) = (
+            "status",
+            "print the failed units with their acknowledgement, silence and notification state",
+        );
+        const NOTIFY_UNIT: (&str, &str) = (
+            "notify-unit",
+            "notify about the current state of a single unit and record it, e.g. as 'OnFailure=' handler",
+        );
+        const OUTBOX_MAX_AGE: (&str, &str, &str) = (
+            "outbox-max-age",
+            "SYSTEMD_FAIL_NOTIFICATIONS_OUTBOX_MAX_AGE",
+            "the maximum age in seconds of a notification that could not be delivered yet, before it is dropped",
+        );
+        const UNIT_TYPES: (&str, &str, &str) = (
+            "unit-types",
+            "SYSTEMD_FAIL_NOTIFICATIONS_UNIT_TYPES",
+            "a whitespace separated list of the types of units to watch, e.g. 'service timer mount'",
+        );
+        const INCLUDE_UNITS: (&str, &str, &str) = (
+            "include-units",
+            "SYSTEMD_FAIL_NOTIFICATIONS_INCLUDE_UNITS",
+            "a whitespace separated list of glob patterns (or regular expressions prefixed with 're:'); if set, only matching units are watched",
+        );
+        const EXCLUDE_UNITS: (&str, &str, &str) = (
+            "exclude-units",
+            "SYSTEMD_FAIL_NOTIFICATIONS_EXCLUDE_UNITS",
+            "a whitespace separated list of glob patterns (or regular expressions prefixed with 're:') of units that are never watched",
+        );
+        const ROUTES: (&str, &str, &str) = (
+            "routes",
+            "SYSTEMD_FAIL_NOTIFICATIONS_ROUTES",
+            "routes like 'discord postgresql*.service; pager * critical' that send notifications about matching units only to the named providers (default: all to all)",
+        );
+        const AGGREGATION_WINDOW: (&str, &str, &str) = (
+            "aggregation-window",
+            "SYSTEMD_FAIL_NOTIFICATIONS_AGGREGATION_WINDOW",
+            "the time in seconds that changes of units are collected before notifying, so that repeated changes of a unit are merged",
+        );
+        const FLAP_THRESHOLD: (&str, &str, &str) = (
+            "flap-threshold",
+            "SYSTEMD_FAIL_NOTIFICATIONS_FLAP_THRESHOLD",
+            "the number of changes of a unit within the flap window after which it is considered flapping (0 disables the detection)",
+        );
+        const FLAP_WINDOW: (&str, &str, &str) = (
+            "flap-window",
+            "SYSTEMD_FAIL_NOTIFICATIONS_FLAP_WINDOW",
+            "the time in seconds in which changes are counted for the flap detection",
+        );
+        const REMINDER_INTERVAL: (&str, &str, &str) = (
+            "reminder-interval",
+            "SYSTEMD_FAIL_NOTIFICATIONS_REMINDER_INTERVAL",
+            "the interval in seconds for repeated notifications about units that stay failed (0 disables reminders)",
+        );
+        const POLL_INTERVAL: (&str, &str, &str) = (
+            "poll-interval",
+            "SYSTEMD_FAIL_NOTIFICATIONS_POLL_INTERVAL",
+            "the interval in milliseconds in which systemd is polled for changes of units",
+        );
+        const DBUS_TIMEOUT: (&str, &str, &str) = (
+            "dbus-timeout",
+            "SYSTEMD_FAIL_NOTIFICATIONS_DBUS_TIMEOUT",
+            "the time in milliseconds after which a D-Bus call to systemd fails",
+        );
+        const RECONNECT_BUDGET: (&str, &str, &str) = (
+            "reconnect-budget",
+            "SYSTEMD_FAIL_NOTIFICATIONS_RECONNECT_BUDGET",
+            "the time in seconds for which the connection to systemd is re-established after it was lost, before terminating with an error (0 terminates on the first error)",
+        );
+        use clap::{builder::FalseyValueParser, Arg, ArgAction, Command};
         let matches = Command::new(env!("CARGO_PKG_NAME"))
             .version(env!("CARGO_PKG_VERSION"))
             .about(env!("CARGO_PKG_DESCRIPTION"))
+            .arg(
+                Arg::new(CONFIG.0)
+                    .long(CONFIG.0)
+                    .env(CONFIG.1)
+                    .help(CONFIG.2)
+                    .global(true)
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new(ABOUT.0)
                     .short(ABOUT.1)
                     .long(ABOUT.0)
                     .help(ABOUT.2)
-                    .takes_value(false),
+                    .action(ArgAction::SetTrue),
             )
             .arg(
                 Arg::new(DISABLE_START_NOTIFICATION.0)
                     .long(DISABLE_START_NOTIFICATION.0)
                     .help(DISABLE_START_NOTIFICATION.1)
-                    .takes_value(false),
+                    .action(ArgAction::SetTrue)
+                    .value_parser(FalseyValueParser::new()),
+            )
+            .arg(
+                Arg::new(ONCE.0)
+                    .long(ONCE.0)
+                    .help(ONCE.1)
+                    .action(ArgAction::SetTrue),
             )
             .arg(
                 Arg::new(DISCORD_WEBHOOK_URL.0)
                     .long(DISCORD_WEBHOOK_URL.0)
                     .env(DISCORD_WEBHOOK_URL.1)
+                    .hide_env_values(true)
                     .help(DISCORD_WEBHOOK_URL.2)
                     .takes_value(true),
             )
+            .arg(
+                Arg::new(DISCORD_WEBHOOK_URL_FILE.0)
+                    .long(DISCORD_WEBHOOK_URL_FILE.0)
+                    .env(DISCORD_WEBHOOK_URL_FILE.1)
+                    .help(DISCORD_WEBHOOK_URL_FILE.2)
+                    .conflicts_with(DISCORD_WEBHOOK_URL.0)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(DISCORD_BATCH.0)
+                    .long(DISCORD_BATCH.0)
+                    .env(DISCORD_BATCH.1)
+                    .help(DISCORD_BATCH.2)
+                    .action(ArgAction::SetTrue)
+                    .value_parser(FalseyValueParser::new()),
+            )
+            .arg(
+                Arg::new(DISCORD_ESCALATE_AFTER.0)
+                    .long(DISCORD_ESCALATE_AFTER.0)
+                    .env(DISCORD_ESCALATE_AFTER.1)
+                    .help(DISCORD_ESCALATE_AFTER.2)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(DISCORD_QUIET_HOURS.0)
+                    .long(DISCORD_QUIET_HOURS.0)
+                    .env(DISCORD_QUIET_HOURS.1)
+                    .help(DISCORD_QUIET_HOURS.2)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(DISCORD_QUIET_HOURS_BYPASS.0)
+                    .long(DISCORD_QUIET_HOURS_BYPASS.0)
+                    .env(DISCORD_QUIET_HOURS_BYPASS.1)
+                    .help(DISCORD_QUIET_HOURS_BYPASS.2)
+                    .default_value("critical")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new(STATE_FILE_PATH.0)
                     .long(STATE_FILE_PATH.0)
                     .env(STATE_FILE_PATH.1)
                     .help(STATE_FILE_PATH.2)
                     .default_value("/var/lib/systemd-fail-notifications/state.json")
+                    .global(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(CONTROL_SOCKET_PATH.0)
+                    .long(CONTROL_SOCKET_PATH.0)
+                    .env(CONTROL_SOCKET_PATH.1)
+                    .help(CONTROL_SOCKET_PATH.2)
+                    .global(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(OUTBOX_MAX_AGE.0)
+                    .long(OUTBOX_MAX_AGE.0)
+                    .env(OUTBOX_MAX_AGE.1)
+                    .help(OUTBOX_MAX_AGE.2)
+                    .default_value("86400")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(UNIT_TYPES.0)
+                    .long(UNIT_TYPES.0)
+                    .env(UNIT_TYPES.1)
+                    .help(UNIT_TYPES.2)
+                    .default_value("service")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(INCLUDE_UNITS.0)
+                    .long(INCLUDE_UNITS.0)
+                    .env(INCLUDE_UNITS.1)
+                    .help(INCLUDE_UNITS.2)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(EXCLUDE_UNITS.0)
+                    .long(EXCLUDE_UNITS.0)
+                    .env(EXCLUDE_UNITS.1)
+                    .help(EXCLUDE_UNITS.2)
                     .takes_value(true),
             )
+            .arg(
+                Arg::new(ROUTES.0)
+                    .long(ROUTES.0)
+                    .env(ROUTES.1)
+                    .help(ROUTES.2)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(AGGREGATION_WINDOW.0)
+                    .long(AGGREGATION_WINDOW.0)
+                    .env(AGGREGATION_WINDOW.1)
+                    .help(AGGREGATION_WINDOW.2)
+                    .default_value("0")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(FLAP_THRESHOLD.0)
+                    .long(FLAP_THRESHOLD.0)
+                    .env(FLAP_THRESHOLD.1)
+                    .help(FLAP_THRESHOLD.2)
+                    .default_value("5")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(FLAP_WINDOW.0)
+                    .long(FLAP_WINDOW.0)
+                    .env(FLAP_WINDOW.1)
+                    .help(FLAP_WINDOW.2)
+                    .default_value("600")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(REMINDER_INTERVAL.0)
+                    .long(REMINDER_INTERVAL.0)
+                    .env(REMINDER_INTERVAL.1)
+                    .help(REMINDER_INTERVAL.2)
+                    .default_value("0")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(POLL_INTERVAL.0)
+                    .long(POLL_INTERVAL.0)
+                    .env(POLL_INTERVAL.1)
+                    .help(POLL_INTERVAL.2)
+                    .default_value("2000")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(DBUS_TIMEOUT.0)
+                    .long(DBUS_TIMEOUT.0)
+                    .env(DBUS_TIMEOUT.1)
+                    .help(DBUS_TIMEOUT.2)
+                    .default_value("10000")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new(RECONNECT_BUDGET.0)
+                    .long(RECONNECT_BUDGET.0)
+                    .env(RECONNECT_BUDGET.1)
+                    .help(RECONNECT_BUDGET.2)
+                    .default_value("600")
+                    .takes_value(true),
+            )
+            .subcommand(
+                Command::new(ACKNOWLEDGE.0)
+                    .alias(ACKNOWLEDGE.1)
+                    .about(ACKNOWLEDGE.2)
+                    .arg(
+                        Arg::new("unit")
+                            .help("the name of the failed unit, e.g. 'example.service'")
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                Command::new(SILENCE.0)
+                    .about(SILENCE.1)
+                    .subcommand_required(true)
+                    .subcommand(
+                        Command::new("add")
+                            .about("add a new silence")
+                            .arg(
+                                Arg::new("units")
+                                    .help("a glob pattern for the names of the silenced units, e.g. 'postgresql*.service'")
+                                    .required(true),
+                            )
+                            .arg(
+                                Arg::new("host")
+                                    .long("host")
+                                    .help("only silence the units on the host with this name")
+                                    .takes_value(true),
+                            )
+                            .arg(
+                                Arg::new("start")
+                                    .long("start")
+                                    .help("the begin of the silence as RFC3339 date and time (default: now)")
+                                    .takes_value(true),
+                            )
+                            .arg(
+                                Arg::new("end")
+                                    .long("end")
+                                    .help("the end of the silence as RFC3339 date and time")
+                                    .required_unless_present("duration")
+                                    .conflicts_with("duration")
+                                    .takes_value(true),
+                            )
+                            .arg(
+                                Arg::new("duration")
+                                    .long("duration")
+                                    .help("the duration of the silence in seconds")
+                                    .takes_value(true),
+                            )
+                            .arg(
+                                Arg::new("comment")
+                                    .long("comment")
+                                    .help("a comment that describes the reason for the silence")
+                                    .default_value("")
+                                    .takes_value(true),
+                            ),
+                    )
+                    .subcommand(
+                        Command::new("remove")
+                            .about("end a silence now and notify about the changes it suppressed")
+                            .arg(
+                                Arg::new("id")
+                                    .help("the ID of the silence")
+                                    .required(true),
+                            ),
+                    )
+                    .subcommand(Command::new("list").about("list all silences")),
+            )
+            .subcommand(
+                Command::new(CHECK_CONFIG.0).about(CHECK_CONFIG.1).arg(
+                    Arg::new("connectivity")
+                        .long("connectivity")
+                        .help("also check that every notification provider is reachable, without sending a message")
+                        .action(ArgAction::SetTrue),
+                ),
+            )
+            .subcommand(
+                Command::new(TEST_NOTIFICATION.0)
+                    .about(TEST_NOTIFICATION.1)
+                    .arg(
+                        Arg::new("unit")
+                            .long("unit")
+                            .help("the name of the synthetic unit")
+                            .default_value("test-notification.service")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("load-state")
+                            .long("load-state")
+                            .help("the load state of the synthetic unit")
+                            .default_value("loaded")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("active-state")
+                            .long("active-state")
+                            .help("the active state of the synthetic unit")
+                            .default_value("failed")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("sub-state")
+                            .long("sub-state")
+                            .help("the sub state of the synthetic unit")
+                            .default_value("failed")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("old-active-state")
+                            .long("old-active-state")
+                            .help("the previous active state of the synthetic unit, e.g. 'failed' together with '--active-state active --sub-state running' for a recovery")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                Command::new(STATUS.0)
+                    .about(STATUS.1)
+                    .arg(
+                        Arg::new("live")
+                            .long("live")
+                            .help("query the failed units from systemd instead of the state file")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("json")
+                            .long("json")
+                            .help("print the failed units as JSON")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                Command::new(NOTIFY_UNIT.0).about(NOTIFY_UNIT.1).arg(
+                    Arg::new("unit")
+                        .help("the name of the unit, e.g. 'example.service'")
+                        .required(true),
+                ),
+            )
             .get_matches();
 
+        let file = match matches.value_of(CONFIG.0) {
+            Some(path) => ConfigFile::load(Path::new(path))?,
+            None => ConfigFile::default(),
+        };
+
+        let state_file_path: String = resolve(&matches, STATE_FILE_PATH.0, file.state_file_path)?
+            .expect("illegal state: no default value present for STATE_FILE_PATH");
+        let control_socket_path =
+            match resolve(&matches, CONTROL_SOCKET_PATH.0, file.control_socket_path)? {
+                Some(path) => path,
+                None => Path::new(&state_file_path)
+                    .with_extension("sock")
+                    .to_string_lossy()
+                    .to_string(),
+            };
+        let command = match matches.subcommand() {
+            Some((name, sub_matches)) if name == ACKNOWLEDGE.0 => {
+                Some(Subcommand::Control(ControlCommand::Acknowledge {
+                    unit: sub_matches
+                        .value_of("unit")
+                        .expect("illegal state: required unit is missing")
+                        .to_string(),
+                }))
+            }
+            Some((name, sub_matches)) if name == SILENCE.0 => Some(Subcommand::Control(
+                silence_command(sub_matches).context("could not parse silence command")?,
+            )),
+            Some((name, sub_matches)) if name == TEST_NOTIFICATION.0 => {
+                let value = |name: &str| {
+                    sub_matches
+                        .value_of(name)
+                        .expect("illegal state: no default value present for test notification")
+                        .to_string()
+                };
+                Some(Subcommand::TestNotification(TestUnit {
+                    name: value("unit"),
+                    load_state: value("load-state"),
+                    active_state: value("active-state"),
+                    sub_state: value("sub-state"),
+                    old_active_state: option_str_to_string(
+                        sub_matches.value_of("old-active-state"),
+                    ),
+                }))
+            }
+            Some((name, sub_matches)) if name == NOTIFY_UNIT.0 => Some(Subcommand::NotifyUnit {
+                unit: sub_matches
+                    .value_of("unit")
+                    .expect("illegal state: required unit is missing")
+                    .to_string(),
+            }),
+            Some((name, sub_matches)) if name == STATUS.0 => Some(Subcommand::Status {
+                live: is_set(sub_matches, "live"),
+                json: is_set(sub_matches, "json"),
+            }),
+            Some((name, sub_matches)) if name == CHECK_CONFIG.0 => Some(Subcommand::CheckConfig {
+                connectivity: is_set(sub_matches, "connectivity"),
+            }),
+            _ => None,
+        };
+
+        // the Discord options of the command line and the environment configure the only Discord provider
+        let mut providers = file.providers;
+        let discord_options_set = [
+            DISCORD_WEBHOOK_URL.0,
+            DISCORD_WEBHOOK_URL_FILE.0,
+            DISCORD_BATCH.0,
+            DISCORD_ESCALATE_AFTER.0,
+            DISCORD_QUIET_HOURS.0,
+            DISCORD_QUIET_HOURS_BYPASS.0,
+        ]
+        .iter()
+        .any(|name| is_explicit(&matches, name));
+        let discord_name = discord_provider_name(&providers, discord_options_set)?;
+        let webhook_url = explicit_value(&matches, DISCORD_WEBHOOK_URL.0)
+            .map(|webhook_url| Secret::new(webhook_url.to_string()));
+        let webhook_url_file = matches
+            .value_of(DISCORD_WEBHOOK_URL_FILE.0)
+            .map(PathBuf::from);
+        if webhook_url.is_some() || webhook_url_file.is_some() {
+            let discord = providers.entry(discord_name.clone()).or_default();
+            discord.webhook_url = webhook_url;
+            discord.webhook_url_file = webhook_url_file;
+        } else if !providers.contains_key(&discord_name)
+            && Secret::from_credential(&webhook_url_credential(&discord_name))?.is_some()
+        {
+            providers.insert(discord_name.clone(), ProviderSection::default());
+        }
+        if let Some(discord) = providers.get_mut(&discord_name) {
+            discord.batch = resolve_flag(&matches, DISCORD_BATCH.0, Some(discord.batch));
+            discord.escalate_after =
+                resolve(&matches, DISCORD_ESCALATE_AFTER.0, discord.escalate_after)?;
+            discord.quiet_hours =
+                resolve(&matches, DISCORD_QUIET_HOURS.0, discord.quiet_hours.take())?;
+            discord.quiet_hours_bypass = resolve(
+                &matches,
+                DISCORD_QUIET_HOURS_BYPASS.0,
+                discord.quiet_hours_bypass,
+            )?;
+        }
+        let providers = providers
+            .into_iter()
+            .map(|(name, section)| provider_config(name, section))
+            .collect::<Result<Vec<ProviderConfig>>>()?;
+        // escalation tiers are only notified about units that stay failed, so the first notification needs another provider
+        if !providers.is_empty()
+            && providers.iter().all(|provider| match &provider.settings {
+                ProviderSettings::Discord(discord) => discord.escalate_after.is_some(),
+            })
+        {
+            return Err(anyhow!(
+                "all notification providers are escalation tiers (escalate-after), at least one provider without it is required"
+            ));
+        }
+
+        let unit_filter = UnitFilter {
+            unit_types: match explicit_value(&matches, UNIT_TYPES.0) {
+                Some(unit_types) => unit_types.split_whitespace().map(str::to_string).collect(),
+                None => match file.units.types {
+                    Some(unit_types) => unit_types,
+                    None => matches
+                        .value_of(UNIT_TYPES.0)
+                        .expect("illegal state: no default value present for UNIT_TYPES")
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect(),
+                },
+            }
+            .into_iter()
+            .map(|unit_type| unit_type.trim_start_matches('.').to_string())
+            .collect(),
+            include: match explicit_value(&matches, INCLUDE_UNITS.0) {
+                Some(patterns) => parse_patterns(patterns)
+                    .context(format!("could not parse {}", INCLUDE_UNITS.0))?,
+                None => file.units.include,
+            },
+            exclude: match explicit_value(&matches, EXCLUDE_UNITS.0) {
+                Some(patterns) => parse_patterns(patterns)
+                    .context(format!("could not parse {}", EXCLUDE_UNITS.0))?,
+                None => file.units.exclude,
+            },
+        };
+
+        let routes = match explicit_value(&matches, ROUTES.0) {
+            Some(routes) => {
+                Routes::parse(routes).context(format!("could not parse {}", ROUTES.0))?
+            }
+            None => Routes(file.routes),
+        };
+        let provider_names: Vec<&str> = providers
+            .iter()
+            .map(|provider| provider.name.as_str())
+            .collect();
+        routes.validate(&provider_names).context("invalid routes")?;
+
+        let seconds = |name: &str, file_value: Option<u64>| -> Result<Duration> {
+            Ok(Duration::from_secs(
+                resolve(&matches, name, file_value)?
+                    .expect("illegal state: no default value present"),
+            ))
+        };
+
+        let milliseconds = |name: &str, file_value: Option<u64>| -> Result<Duration> {
+            match resolve(&matches, name, file_value)?
+                .expect("illegal state: no default value present")
+            {
+                0 => Err(anyhow!("{} must be greater than 0", name)),
+                value => Ok(Duration::from_millis(value)),
+            }
+        };
+
         Ok(Self {
-            discord_webhook_url: option_str_to_string(matches.value_of(DISCORD_WEBHOOK_URL.0)),
-            state_file_path: matches
-                .value_of(STATE_FILE_PATH.0)
-                .expect("illegal state: no default value present for STATE_FILE_PATH")
-                .to_string(),
-            about: matches.is_present(ABOUT.0),
-            disable_start_notification: matches.is_present(DISABLE_START_NOTIFICATION.0),
+            providers,
+            outbox_max_age: seconds(OUTBOX_MAX_AGE.0, file.outbox_max_age)?,
+            state_file_path,
+            control_socket_path,
+            unit_filter,
+            rules: file.rules.map(Rules).unwrap_or_default(),
+            routes,
+            aggregation_window: seconds(AGGREGATION_WINDOW.0, file.aggregation_window)?,
+            flap_threshold: resolve(&matches, FLAP_THRESHOLD.0, file.flap_threshold)?
+                .expect("illegal state: no default value present for FLAP_THRESHOLD"),
+            flap_window: seconds(FLAP_WINDOW.0, file.flap_window)?,
+            reminder_interval: match seconds(REMINDER_INTERVAL.0, file.reminder_interval)? {
+                Duration::ZERO => None,
+                interval => Some(interval),
+            },
+            poll_interval: milliseconds(POLL_INTERVAL.0, file.poll_interval)?,
+            dbus_timeout: milliseconds(DBUS_TIMEOUT.0, file.dbus_timeout)?,
+            reconnect_budget: seconds(RECONNECT_BUDGET.0, file.reconnect_budget)?,
+            about: is_set(&matches, ABOUT.0),
+            disable_start_notification: resolve_flag(
+                &matches,
+                DISABLE_START_NOTIFICATION.0,
+                file.disable_start_notification,
+            ),
+            once: is_set(&matches, ONCE.0),
+            command,
         })
     }
 }
 
+/// Converts the section of a provider in the configuration file to its configuration.
+fn provider_config(name: String, section: ProviderSection) -> Result<ProviderConfig> {
+    let bypass_severity = section.quiet_hours_bypass.unwrap_or(Severity::Critical);
+    let webhook_url = match (section.webhook_url, section.webhook_url_file) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "providers.{} must not set both webhook-url and webhook-url-file",
+                name
+            ))
+        }
+        (Some(webhook_url), None) => webhook_url,
+        (None, Some(path)) => Secret::from_file(&path).context(format!(
+            "could not read providers.{}.webhook-url-file",
+            name
+        ))?,
+        (None, None) => Secret::from_credential(&webhook_url_credential(&name))?
+            .ok_or_else(|| anyhow!("providers.{} has no webhook-url", name))?,
+    };
+    let settings = match section.provider_type {
+        ProviderType::Discord => ProviderSettings::Discord(DiscordConfig {
+            quiet_hours: section
+                .quiet_hours
+                .map(|schedule| QuietHours::parse(&schedule, bypass_severity, None))
+                .transpose()
+                .context(format!("could not parse providers.{}.quiet-hours", name))?,
+            webhook_url,
+            batch: section.batch,
+            escalate_after: section.escalate_after.map(Duration::from_secs),
+        }),
+    };
+    Ok(ProviderConfig { name, settings })
+}
+
+/// Returns the name of the provider that the Discord options of the command line and the environment configure:
+/// the only Discord provider of the configuration file, regardless of its name, or otherwise `discord`.
+/// If the options are set, several Discord providers in the configuration file are an error,
+/// as it is ambiguous which of them the options configure.
+fn discord_provider_name(
+    providers: &BTreeMap<String, ProviderSection>,
+    options_set: bool,
+) -> Result<String> {
+    let names: Vec<&str> = providers
+        .iter()
+        .filter(|(_, section)| matches!(section.provider_type, ProviderType::Discord))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    match names.as_slice() {
+        [name] => Ok(name.to_string()),
+        [_, _, ..] if options_set => Err(anyhow!(
+            "the Discord options of the command line and the environment can not be applied, as the configuration file has several Discord providers ({})",
+            names.join(", ")
+        )),
+        _ => Ok(DISCORD_PROVIDER_NAME.to_string()),
+    }
+}
+
+/// Returns the name of the systemd credential that holds the webhook URL of the named provider.
+fn webhook_url_credential(provider_name: &str) -> String {
+    format!("{}-webhook-url", provider_name)
+}
+
+/// Returns true, if the argument was set on the command line or by an environment variable.
+fn is_explicit(matches: &clap::ArgMatches, name: &str) -> bool {
+    !matches!(
+        matches.value_source(name),
+        Some(clap::ValueSource::DefaultValue) | None
+    )
+}
+
+/// Returns the value of the argument, if it was set on the command line or by an environment variable.
+fn explicit_value<'a>(matches: &'a clap::ArgMatches, name: &str) -> Option<&'a str> {
+    if is_explicit(matches, name) {
+        matches.value_of(name)
+    } else {
+        None
+    }
+}
+
+/// Returns true, if the flag is set.
+fn is_set(matches: &clap::ArgMatches, name: &str) -> bool {
+    matches.get_one::<bool>(name).copied().unwrap_or(false)
+}
+
+/// Returns the parsed value of the argument.
+/// A value set on the command line or by an environment variable takes precedence over the value of the
+/// configuration file, which takes precedence over the default value of the argument.
+fn resolve<T>(matches: &clap::ArgMatches, name: &str, file_value: Option<T>) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let parse = |value: &str| {
+        value
+            .parse::<T>()
+            .map_err(|error| anyhow!("could not parse {} '{}': {}", name, value, error))
+    };
+    match explicit_value(matches, name) {
+        Some(value) => parse(value).map(Some),
+        None => match file_value {
+            Some(value) => Ok(Some(value)),
+            None => matches.value_of(name).map(parse).transpose(),
+        },
+    }
+}
+
+/// Returns the value of the flag, which is resolved like the values of [`resolve`].
+/// Unlike checking for the presence of the flag, an environment variable set to e.g. `false` disables a flag
+/// that is enabled in the configuration file.
+fn resolve_flag(matches: &clap::ArgMatches, name: &str, file_value: Option<bool>) -> bool {
+    if is_explicit(matches, name) {
+        is_set(matches, name)
+    } else {
+        file_value.unwrap_or(false)
+    }
+}
+
+/// Creates the control command for the subcommands of the silence subcommand.
+fn silence_command(matches: &clap::ArgMatches) -> Result<ControlCommand> {
+    match matches.subcommand() {
+        Some(("add", matches)) => {
+            let starts_at = match matches.value_of("start") {
+                Some(start) => parse_timestamp(start)?,
+                None => unix_now(),
+            };
+            let ends_at = match (matches.value_of("end"), matches.value_of("duration")) {
+                (Some(end), _) => parse_timestamp(end)?,
+                (None, Some(duration)) => {
+                    starts_at
+                        + duration
+                            .parse::<u64>()
+                            .context("could not parse duration as seconds")?
+                }
+                (None, None) => return Err(anyhow!("either end or duration is required")),
+            };
+            Ok(ControlCommand::Silence {
+                units: matches
+                    .value_of("units")
+                    .expect("illegal state: required units are missing")
+                    .to_string(),
+                host: option_str_to_string(matches.value_of("host")),
+                starts_at,
+                ends_at,
+                comment: matches
+                    .value_of("comment")
+                    .expect("illegal state: no default value present for comment")
+                    .to_string(),
+            })
+        }
+        Some(("remove", matches)) => Ok(ControlCommand::Unsilence {
+            id: matches
+                .value_of("id")
+                .expect("illegal state: required id is missing")
+                .parse()
+                .context("could not parse id as number")?,
+        }),
+        Some(("list", _)) => Ok(ControlCommand::ListSilences),
+        _ => Err(anyhow!("unknown silence command")),
+    }
+}
+
+/// Parses a whitespace separated list of patterns.
+fn parse_patterns(patterns: &str) -> Result<Vec<Pattern>> {
+    patterns.split_whitespace().map(Pattern::parse).collect()
+}
+
+/// Parses an RFC3339 date and time as unix timestamp in seconds.
+fn parse_timestamp(value: &str) -> Result<u64> {
+    let date_time =
+        time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339)
+            .context(format!(
+                "could not parse '{}' as RFC3339 date and time",
+                value
+            ))?;
+    std::convert::TryFrom::try_from(date_time.unix_timestamp())
+        .context(format!("'{}' is before the unix epoch", value))
+}
+
 fn option_str_to_string(value: Option<&str>) -> Option<String> {
     value.map(|val| val.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::{builder::FalseyValueParser, Arg, ArgAction, Command};
+
+    use super::*;
+
+    #[test]
+    fn flag_from_environment_overrides_configuration_file() {
+        let command = || {
+            Command::new("test").arg(
+                Arg::new("flag")
+                    .long("flag")
+                    .env("SYSTEMD_FAIL_NOTIFICATIONS_TEST_FLAG")
+                    .action(ArgAction::SetTrue)
+                    .value_parser(FalseyValueParser::new()),
+            )
+        };
+        let matches = command().get_matches_from(["test"]);
+        assert!(!resolve_flag(&matches, "flag", None));
+        assert!(resolve_flag(&matches, "flag", Some(true)));
+        let matches = command().get_matches_from(["test", "--flag"]);
+        assert!(resolve_flag(&matches, "flag", Some(false)));
+
+        std::env::set_var("SYSTEMD_FAIL_NOTIFICATIONS_TEST_FLAG", "false");
+        let matches = command().get_matches_from(["test"]);
+        assert!(!resolve_flag(&matches, "flag", Some(true)));
+        std::env::set_var("SYSTEMD_FAIL_NOTIFICATIONS_TEST_FLAG", "1");
+        let matches = command().get_matches_from(["test"]);
+        assert!(resolve_flag(&matches, "flag", Some(false)));
+        std::env::remove_var("SYSTEMD_FAIL_NOTIFICATIONS_TEST_FLAG");
+    }
+
+    #[test]
+    fn discord_options_configure_the_only_discord_provider() {
+        let mut providers = BTreeMap::new();
+        assert_eq!(discord_provider_name(&providers, true).unwrap(), "discord");
+        providers.insert("alerts".to_string(), ProviderSection::default());
+        assert_eq!(discord_provider_name(&providers, true).unwrap(), "alerts");
+        providers.insert("ops".to_string(), ProviderSection::default());
+        assert!(discord_provider_name(&providers, true).is_err());
+        assert_eq!(discord_provider_name(&providers, false).unwrap(), "discord");
+    }
+}
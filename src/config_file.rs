@@ -0,0 +1,177 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    notifications::Severity, pattern::Pattern, routing::Route, rules::Rule, secret::Secret,
+};
+
+/// The content of the optional TOML configuration file.
+/// The keys are named like the corresponding command line options and all of them are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub state_file_path: Option<String>,
+    pub control_socket_path: Option<String>,
+    pub outbox_max_age: Option<u64>,
+    pub aggregation_window: Option<u64>,
+    pub flap_threshold: Option<usize>,
+    pub flap_window: Option<u64>,
+    pub reminder_interval: Option<u64>,
+    pub poll_interval: Option<u64>,
+    pub dbus_timeout: Option<u64>,
+    pub reconnect_budget: Option<u64>,
+    pub disable_start_notification: Option<bool>,
+    #[serde(default)]
+    pub units: UnitsSection,
+    /// Replaces the default rules, if set.
+    pub rules: Option<Vec<Rule>>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// All notification providers by their name.
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderSection>,
+}
+
+/// Selects the units of interest, see [`crate::filter::UnitFilter`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UnitsSection {
+    pub types: Option<Vec<String>>,
+    #[serde(default)]
+    pub include: Vec<Pattern>,
+    #[serde(default)]
+    pub exclude: Vec<Pattern>,
+}
+
+/// The configuration of a single notification provider.
+/// The options next to the `type` depend on the type of the provider.
+// not an internally tagged enum, as those lose the position of errors in the TOML document
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProviderSection {
+    #[serde(rename = "type")]
+    pub provider_type: ProviderType,
+    /// If neither this nor the file is set, the systemd credential `<name>-webhook-url` is used.
+    pub webhook_url: Option<Secret>,
+    pub webhook_url_file: Option<PathBuf>,
+    #[serde(default)]
+    pub batch: bool,
+    /// In seconds.
+    pub escalate_after: Option<u64>,
+    pub quiet_hours: Option<String>,
+    pub quiet_hours_bypass: Option<Severity>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderType {
+    #[default]
+    Discord,
+}
+
+impl ConfigFile {
+    /// Reads and parses the configuration file at the given path.
+    /// Errors point to the line and key of the offending value.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context(format!("could not read configuration file {:?}", path))?;
+        Self::parse(&content).context(format!("could not parse configuration file {:?}", path))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_configuration_is_parsed() {
+        let file = ConfigFile::parse(
+            r#"
+            flap-threshold = 3
+            reminder-interval = 3600
+
+            [units]
+            types = ["service", "timer"]
+            exclude = ["re:systemd-.*-wait-online\\.service"]
+
+            [[rules]]
+            unit = "postgresql*.service"
+            action = { severity = "critical" }
+
+            [[rules]]
+            new-active-state = "failed"
+            action = "notify"
+
+            [[routes]]
+            providers = ["database"]
+            units = "postgresql*.service"
+
+            [[routes]]
+            providers = ["platform"]
+            min-severity = "warning"
+
+            [providers.database]
+            type = "discord"
+            webhook-url = "https://discord.com/api/webhooks/1/a"
+            batch = true
+
+            [providers.platform]
+            type = "discord"
+            webhook-url = "https://discord.com/api/webhooks/2/b"
+            quiet-hours = "22:00-07:00"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.flap_threshold, Some(3));
+        assert_eq!(file.reminder_interval, Some(3600));
+        assert_eq!(file.units.exclude.len(), 1);
+        assert_eq!(file.rules.unwrap().len(), 2);
+        assert_eq!(file.routes.len(), 2);
+        assert_eq!(
+            file.providers.keys().collect::<Vec<&String>>(),
+            vec!["database", "platform"]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_offending_key() {
+        let error = ConfigFile::parse("[providers.db]\ntype = \"discord\"\nwebhook-url = 1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.contains("webhook-url"), "{}", error);
+
+        let error = ConfigFile::parse(
+            "[providers.db]\ntype = \"discord\"\nwebhook-url = \"\"\nbach = true\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("unknown field `bach`"), "{}", error);
+
+        let error = ConfigFile::parse("flap-treshold = 3\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `flap-treshold`"), "{}", error);
+
+        let error = ConfigFile::parse("[[rules]]\nunit = \"re:(\"\naction = \"notify\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+}
@@ -0,0 +1,447 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifications::UnitNotification,
+    outbox::Outbox,
+    silences::Silences,
+    state::{unix_now, SystemdState, SystemdStateImpl},
+    status::UnitStatus,
+};
+
+/// The time a client waits for the daemon to handle a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A command that changes the state of the daemon, e.g. sent by a CLI subcommand over the control socket.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ControlCommand {
+    /// Acknowledge the current failure of the unit, which suppresses reminders and escalations until it recovers.
+    Acknowledge { unit: String },
+    /// Add a silence for all units matching the glob pattern between the two unix timestamps (in seconds).
+    Silence {
+        units: String,
+        host: Option<String>,
+        starts_at: u64,
+        ends_at: u64,
+        comment: String,
+    },
+    /// End the silence with the given ID now, which notifies about the changes it suppressed.
+    Unsilence { id: u64 },
+    /// List all silences that did not expire yet.
+    ListSilences,
+    /// Record the status of a unit that was already notified about, so that its change is not notified again.
+    Record { status: UnitStatus },
+    /// Record the change of a unit as suppressed by the matching silence, so that it is part of its summary.
+    Suppress { status: UnitStatus },
+    /// Hold the notification for the named provider until the unix timestamp (in seconds), e.g. during quiet hours.
+    Hold {
+        provider: String,
+        notification: UnitNotification,
+        until: u64,
+    },
+}
+
+/// The result of a [`ControlCommand`] as sent back to the client.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Ok(String),
+    Error(String),
+}
+
+impl ControlCommand {
+    /// Applies the command to the state and returns a human readable description of the result.
+    pub fn apply<S: SystemdState>(
+        &self,
+        systemd: &mut S,
+        silences: &mut Silences,
+        outbox: &mut Outbox,
+    ) -> Result<String> {
+        match self {
+            ControlCommand::Acknowledge { unit } => {
+                systemd.acknowledge(unit)?;
+                Ok(format!(
+                    "acknowledged the failure of {}: no more reminders or escalations are sent until it recovers",
+                    unit
+                ))
+            }
+            ControlCommand::Silence {
+                units,
+                host,
+                starts_at,
+                ends_at,
+                comment,
+            } => {
+                let silence = silences.add(
+                    units.clone(),
+                    host.clone(),
+                    *starts_at,
+                    *ends_at,
+                    comment.clone(),
+                )?;
+                Ok(format!(
+                    "added silence {} for {}: changes are suppressed from {} until {}",
+                    silence.id,
+                    silence.units,
+                    format_timestamp(silence.starts_at),
+                    format_timestamp(silence.ends_at)
+                ))
+            }
+            ControlCommand::Unsilence { id } => {
+                let silence = silences.end(*id, unix_now())?;
+                Ok(format!(
+                    "ended silence {} for {}: the {} suppressed changes are notified with the next poll",
+                    silence.id,
+                    silence.units,
+                    silence
+                        .suppressed
+                        .iter()
+                        .map(|suppressed| suppressed.count)
+                        .sum::<usize>()
+                ))
+            }
+            ControlCommand::Record { status } => {
+                systemd.record(status.clone())?;
+                Ok(format!(
+                    "recorded the state {} of {}",
+                    status.active_state(),
+                    status.name()
+                ))
+            }
+            ControlCommand::Suppress { status } => {
+                if silences.suppress(status, unix_now())? {
+                    Ok(format!("suppressed the change of {}", status.name()))
+                } else {
+                    Err(anyhow!("no active silence matches {}", status.name()))
+                }
+            }
+            ControlCommand::Hold {
+                provider,
+                notification,
+                until,
+            } => {
+                outbox.hold(provider, vec![notification.clone()], *until)?;
+                Ok(format!(
+                    "held the notification about {} for provider '{}' until {}",
+                    notification.status.name(),
+                    provider,
+                    format_timestamp(*until)
+                ))
+            }
+            ControlCommand::ListSilences => Ok(silences
+                .list()
+                .iter()
+                .filter(|silence| silence.ends_at > unix_now())
+                .map(|silence| {
+                    format!(
+                        "{}\t{}\t{}\t{} - {}\t{} suppressed\t{}",
+                        silence.id,
+                        silence.units,
+                        silence.host.as_deref().unwrap_or("*"),
+                        format_timestamp(silence.starts_at),
+                        format_timestamp(silence.ends_at),
+                        silence
+                            .suppressed
+                            .iter()
+                            .map(|suppressed| suppressed.count)
+                            .sum::<usize>(),
+                        silence.comment
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")),
+        }
+    }
+}
+
+/// Formats a unix timestamp (in seconds) as RFC3339 date and time in UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()
+        .and_then(|date_time| {
+            date_time
+                .format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// A command received over the control socket together with the channel for its response.
+pub type ReceivedCommand = (ControlCommand, mpsc::Sender<ControlResponse>);
+
+/// The server side of the control socket, which accepts commands in a separate thread.
+/// The received commands are handled by the owner of this socket, typically in the main loop.
+pub struct ControlSocket {
+    receiver: mpsc::Receiver<ReceivedCommand>,
+}
+
+impl ControlSocket {
+    /// Binds the control socket to the given path and starts accepting connections in a separate thread.
+    /// Each connection is handled in its own thread, so that an idle client does not block other clients.
+    /// A stale socket file of a previous run is removed beforehand,
+    /// but an error is returned, if another daemon is still listening on it.
+    pub fn bind(path: &Path) -> Result<Self> {
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(anyhow!(
+                    "another daemon is listening on the control socket {:?}",
+                    path
+                ))
+            }
+            Err(error) if error.kind() == ErrorKind::ConnectionRefused => {
+                fs::remove_file(path)
+                    .context(format!("could not remove stale control socket {:?}", path))?;
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => {
+                return Err(error)
+                    .context(format!("could not connect to control socket {:?}", path))
+            }
+        }
+        if let Some(dir_path) = path.parent() {
+            fs::create_dir_all(dir_path).context(format!(
+                "could not create directories for the control socket ({:?})",
+                dir_path
+            ))?;
+        }
+        let listener = UnixListener::bind(path)
+            .context(format!("could not bind control socket {:?}", path))?;
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream.context("could not accept connection") {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        eprintln!("Error on control socket: {:?}", error);
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    if let Err(error) = handle_connection(stream, &sender) {
+                        eprintln!("Error on control socket: {:?}", error);
+                    }
+                });
+            }
+        });
+        Ok(Self { receiver })
+    }
+
+    /// Returns the next received command, if any, without blocking.
+    pub fn try_recv(&self) -> Option<ReceivedCommand> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Reads a single command from the connection, hands it over to the receiver and writes back the response.
+fn handle_connection(stream: UnixStream, sender: &mpsc::Sender<ReceivedCommand>) -> Result<()> {
+    stream
+        .set_read_timeout(Some(RESPONSE_TIMEOUT))
+        .context("could not set read timeout")?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .context("could not read command")?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(command) => {
+            let (response_sender, response_receiver) = mpsc::channel();
+            sender
+                .send((command, response_sender))
+                .context("command receiver is closed")?;
+            response_receiver
+                .recv_timeout(RESPONSE_TIMEOUT)
+                .unwrap_or_else(|_| ControlResponse::Error("command was not handled".to_string()))
+        }
+        Err(error) => ControlResponse::Error(format!("could not parse command: {}", error)),
+    };
+
+    let mut stream = stream;
+    writeln!(
+        stream,
+        "{}",
+        serde_json::to_string(&response).context("could not serialize response")?
+    )
+    .context("could not write response")
+}
+
+/// Sends the command to the daemon listening on the given control socket and returns its response.
+/// Returns `None`, if no daemon is listening on the socket.
+pub fn send(path: &Path, command: &ControlCommand) -> Result<Option<ControlResponse>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(error)
+            if error.kind() == ErrorKind::NotFound
+                || error.kind() == ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None)
+        }
+        Err(error) => {
+            return Err(error).context(format!("could not connect to control socket {:?}", path))
+        }
+    };
+    stream
+        .set_read_timeout(Some(RESPONSE_TIMEOUT))
+        .context("could not set read timeout")?;
+    writeln!(
+        stream,
+        "{}",
+        serde_json::to_string(command).context("could not serialize command")?
+    )
+    .context("could not send command")?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .context("could not read response")?;
+    let response = serde_json::from_str(&line).context("could not parse response")?;
+    Ok(Some(response))
+}
+
+/// Executes the command for a CLI subcommand: it is sent to the running daemon, if there is one.
+/// Otherwise, the command is applied to the state files directly.
+pub fn run(
+    socket_path: &Path,
+    state_file_path: PathBuf,
+    outbox_max_age: Duration,
+    command: ControlCommand,
+) -> Result<()> {
+    let response = match send(socket_path, &command)? {
+        Some(response) => response,
+        None => {
+            let mut silences = Silences::new(Silences::file_path_for_state_file(&state_file_path));
+            let mut outbox = Outbox::new(
+                Outbox::file_path_for_state_file(&state_file_path),
+                outbox_max_age,
+            );
+            match command.apply(
+                &mut SystemdStateImpl::new(state_file_path),
+                &mut silences,
+                &mut outbox,
+            ) {
+                Ok(message) => ControlResponse::Ok(message),
+                Err(error) => ControlResponse::Error(format!("{:#}", error)),
+            }
+        }
+    };
+    match response {
+        ControlResponse::Ok(message) => {
+            println!("{}", message);
+            Ok(())
+        }
+        ControlResponse::Error(message) => Err(anyhow!(message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{temp_file_path, unit_status};
+
+    #[test]
+    fn command_is_received_and_answered() {
+        let path = temp_file_path();
+        let socket = ControlSocket::bind(&path).unwrap();
+        let command = ControlCommand::Acknowledge {
+            unit: "test.service".to_string(),
+        };
+
+        let client_path = path.clone();
+        let client_command = command.clone();
+        let client = thread::spawn(move || send(&client_path, &client_command).unwrap());
+
+        let (received, reply) = loop {
+            if let Some(received) = socket.try_recv() {
+                break received;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(received, command);
+        reply.send(ControlResponse::Ok("done".to_string())).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            Some(ControlResponse::Ok("done".to_string()))
+        );
+    }
+
+    #[test]
+    fn idle_client_does_not_block_other_clients() {
+        let path = temp_file_path();
+        let socket = ControlSocket::bind(&path).unwrap();
+        let _idle = UnixStream::connect(&path).unwrap();
+        let command = ControlCommand::ListSilences;
+
+        let client_path = path.clone();
+        let client_command = command.clone();
+        let client = thread::spawn(move || send(&client_path, &client_command).unwrap());
+
+        let started = std::time::Instant::now();
+        let (received, reply) = loop {
+            if let Some(received) = socket.try_recv() {
+                break received;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(received, command);
+        reply.send(ControlResponse::Ok("done".to_string())).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            Some(ControlResponse::Ok("done".to_string()))
+        );
+    }
+
+    #[test]
+    fn only_stale_socket_is_replaced() {
+        let path = temp_file_path();
+        drop(UnixListener::bind(&path).unwrap());
+        let _socket = ControlSocket::bind(&path).expect("stale socket should be replaced");
+        assert!(ControlSocket::bind(&path).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn recording_without_access_to_state_file_is_an_error() {
+        let command = ControlCommand::Record {
+            status: unit_status("test.service", "failed"),
+        };
+        let state_file_path = Path::new("/proc/state.json").to_path_buf();
+        assert!(run(
+            &temp_file_path(),
+            state_file_path,
+            Duration::from_secs(60),
+            command
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn send_without_daemon_returns_none() {
+        assert_eq!(
+            send(
+                &temp_file_path(),
+                &ControlCommand::Acknowledge {
+                    unit: "test.service".to_string()
+                }
+            )
+            .unwrap(),
+            None
+        );
+    }
+}
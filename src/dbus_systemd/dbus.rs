@@ -4,43 +4,208 @@ SPDX-FileCopyrightText: 2021 localthomas
 SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
-use anyhow::{Context, Result};
-use serde::Deserialize;
-use zvariant::{OwnedObjectPath, Type};
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread, time,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use zvariant::{DynamicType, OwnedObjectPath, OwnedValue, Type};
 
 use super::SystemdConnection;
 
+const SYSTEMD_DESTINATION: &str = "org.freedesktop.systemd1";
+const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+const SYSTEMD_MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+
 pub struct Connection {
     conn: zbus::blocking::Connection,
+    /// The time after which a method call is abandoned.
+    timeout: time::Duration,
+    /// Set while a method call is running, including an abandoned one that did not return yet.
+    pending: Arc<AtomicBool>,
 }
 
 impl Connection {
-    pub fn new() -> Result<Self> {
+    pub fn new(timeout: time::Duration) -> Result<Self> {
         let conn = zbus::blocking::Connection::system().context(format!(
             "could not connect to system bus at {}",
             dbus_system_address()
         ))?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            timeout,
+            pending: Arc::new(AtomicBool::new(false)),
+        })
     }
-}
 
-impl SystemdConnection for Connection {
-    fn list_units(&self) -> Result<Vec<UnitStatusRaw>> {
+    /// Calls the method of the object at the given path of systemd and returns the reply.
+    /// The call is executed in a separate thread, so that it can be abandoned after the timeout of the connection,
+    /// e.g. if systemd hangs. The reply of an abandoned call is dropped once it arrives.
+    /// While an abandoned call did not return, all further calls are refused, so that at most one thread is stuck.
+    fn call_method<B>(
+        &self,
+        path: &str,
+        interface: &str,
+        method: &'static str,
+        body: B,
+    ) -> Result<Arc<zbus::Message>>
+    where
+        B: Serialize + DynamicType + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let path = path.to_string();
+        let interface = interface.to_string();
+        call_with_timeout(&self.pending, self.timeout, method, move || {
+            conn.call_method(
+                Some(SYSTEMD_DESTINATION),
+                path.as_str(),
+                Some(interface.as_str()),
+                method,
+                &body,
+            )
+            .context(format!("could not make method call to {}", method))
+        })
+    }
+
+    /// Returns the value of a property of the object at the given path of systemd.
+    fn property(&self, path: &OwnedObjectPath, interface: &str, name: &str) -> Result<OwnedValue> {
         let message = self
-            .conn
             .call_method(
-                Some("org.freedesktop.systemd1"),
-                "/org/freedesktop/systemd1",
-                Some("org.freedesktop.systemd1.Manager"),
-                "ListUnits",
-                &(),
+                path.as_str(),
+                "org.freedesktop.DBus.Properties",
+                "Get",
+                (interface.to_string(), name.to_string()),
             )
-            .context("could not make method call to ListUnits")?;
+            .context(format!("could not get property {}.{}", interface, name))?;
+        message
+            .body()
+            .context("could not deserialize the message from dbus")
+    }
+}
+
+impl SystemdConnection for Connection {
+    fn list_units(&self) -> Result<Vec<UnitStatusRaw>> {
+        let message = self.call_method(SYSTEMD_PATH, SYSTEMD_MANAGER_INTERFACE, "ListUnits", ())?;
         let unit_status: Vec<UnitStatusInternal> = message
             .body()
             .context("could not deserialize the message from dbus")?;
         Ok(unit_status.into_iter().map(UnitStatusRaw::from).collect())
     }
+
+    fn unit_status(&self, name: &str) -> Result<UnitStatusRaw> {
+        let message = self.call_method(
+            SYSTEMD_PATH,
+            SYSTEMD_MANAGER_INTERFACE,
+            "ListUnitsByNames",
+            (vec![name.to_string()],),
+        )?;
+        let unit_status: Vec<UnitStatusInternal> = message
+            .body()
+            .context("could not deserialize the message from dbus")?;
+        unit_status
+            .into_iter()
+            .next()
+            .map(UnitStatusRaw::from)
+            .ok_or_else(|| anyhow!("systemd returned no status for unit {}", name))
+    }
+
+    fn unit_details(&self, name: &str) -> Result<Vec<(String, String)>> {
+        let message = self.call_method(
+            SYSTEMD_PATH,
+            SYSTEMD_MANAGER_INTERFACE,
+            "GetUnit",
+            (name.to_string(),),
+        )?;
+        let path: OwnedObjectPath = message
+            .body()
+            .context("could not deserialize the message from dbus")?;
+
+        let mut details = Vec::new();
+        let changed_at = self
+            .property(
+                &path,
+                "org.freedesktop.systemd1.Unit",
+                "StateChangeTimestamp",
+            )
+            .and_then(|value| Ok(u64::try_from(value)?))?;
+        details.push((
+            "Changed At".to_string(),
+            crate::control::format_timestamp(changed_at / 1_000_000),
+        ));
+        // the following properties only exist for services
+        const SERVICE_INTERFACE: &str = "org.freedesktop.systemd1.Service";
+        if let Ok(result) = self
+            .property(&path, SERVICE_INTERFACE, "Result")
+            .and_then(|value| Ok(String::try_from(value)?))
+        {
+            details.push(("Result".to_string(), result));
+        }
+        if let Ok(status) = self
+            .property(&path, SERVICE_INTERFACE, "ExecMainStatus")
+            .and_then(|value| Ok(i32::try_from(value)?))
+        {
+            details.push(("Exit Status".to_string(), status.to_string()));
+        }
+        Ok(details)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.conn = Self::new(self.timeout)?.conn;
+        Ok(())
+    }
+}
+
+/// Clears the pending flag of a call once the call returns, even if it panicked.
+struct PendingCall(Arc<AtomicBool>);
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Executes the call in a separate thread and returns its result or an error after the timeout.
+/// If the previous call is still pending, i.e. it was abandoned and did not return yet, the call is refused.
+fn call_with_timeout<T, F>(
+    pending: &Arc<AtomicBool>,
+    timeout: time::Duration,
+    method: &str,
+    call: F,
+) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    if pending.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!(
+            "method call to {} refused, as a previous call that timed out did not return yet",
+            method
+        ));
+    }
+    let guard = PendingCall(pending.clone());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = call();
+        drop(guard);
+        // the receiver is gone, if the call was abandoned
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(anyhow!(
+            "method call to {} timed out after {:?}",
+            method,
+            timeout
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err(anyhow!("method call to {} panicked", method)),
+    }
 }
 
 fn dbus_system_address() -> String {
@@ -89,3 +254,31 @@ struct UnitStatusInternal {
     job_type: String,
     job_object_path: OwnedObjectPath,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_refused_while_abandoned_call_is_pending() {
+        let pending = Arc::new(AtomicBool::new(false));
+        let timeout = time::Duration::from_millis(10);
+        let (release, released) = mpsc::channel::<()>();
+        let result = call_with_timeout(&pending, timeout, "Hanging", move || {
+            released.recv().ok();
+            Ok(1)
+        });
+        assert!(result.is_err());
+        let result = call_with_timeout(&pending, timeout, "Next", || Ok(2));
+        assert!(result.unwrap_err().to_string().contains("refused"));
+
+        release.send(()).unwrap();
+        while pending.load(Ordering::SeqCst) {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert_eq!(
+            call_with_timeout(&pending, timeout, "Next", || Ok(3)).unwrap(),
+            3
+        );
+    }
+}
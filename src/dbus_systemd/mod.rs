@@ -11,6 +11,17 @@ use dbus::UnitStatusRaw;
 
 pub trait SystemdConnection {
     fn list_units(&self) -> Result<Vec<UnitStatusRaw>>;
+
+    /// Returns the status of the unit with the given name, even if it is not loaded.
+    fn unit_status(&self, name: &str) -> Result<UnitStatusRaw>;
+
+    /// Returns details about the last state change of the unit as name and value pairs,
+    /// e.g. the result and exit status of a service.
+    /// Details that are not available for the type of the unit are omitted.
+    fn unit_details(&self, name: &str) -> Result<Vec<(String, String)>>;
+
+    /// Replaces the connection to systemd with a new one, e.g. after the system bus was restarted.
+    fn reconnect(&mut self) -> Result<()>;
 }
 
 #[cfg(test)]
@@ -22,6 +33,8 @@ pub mod tests {
     pub struct MockupSystemdConnection {
         pub units: Vec<UnitStatusRaw>,
         pub error: bool,
+        /// The number of calls to [`SystemdConnection::reconnect`].
+        pub reconnects: usize,
     }
 
     impl MockupSystemdConnection {
@@ -29,6 +42,7 @@ pub mod tests {
             Self {
                 units: Vec::new(),
                 error: false,
+                reconnects: 0,
             }
         }
     }
@@ -41,5 +55,21 @@ pub mod tests {
                 Ok(self.units.to_vec())
             }
         }
+
+        fn unit_status(&self, name: &str) -> Result<UnitStatusRaw> {
+            self.list_units()?
+                .into_iter()
+                .find(|unit| unit.name == name)
+                .ok_or_else(|| anyhow!("unknown unit {}", name))
+        }
+
+        fn unit_details(&self, _name: &str) -> Result<Vec<(String, String)>> {
+            Ok(Vec::new())
+        }
+
+        fn reconnect(&mut self) -> Result<()> {
+            self.reconnects += 1;
+            Ok(())
+        }
     }
 }
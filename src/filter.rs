@@ -5,48 +5,174 @@ SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
 use crate::{
-    state::ChangedUnitStatus,
-    status::{ActiveState, LoadState},
+    notifications::Severity,
+    pattern::Pattern,
+    rules::Rules,
+    silences::Silences,
+    state::{unix_now, ChangedUnitStatus},
+    status::{ActiveState, UnitStatus},
 };
 
+/// Selects the units that are of interest by their type and name.
+#[derive(Debug, Clone)]
+pub struct UnitFilter {
+    /// The types of units, i.e. the suffix of their name like `service` or `timer`.
+    pub unit_types: Vec<String>,
+    /// If not empty, only units that match at least one of these patterns are of interest.
+    pub include: Vec<Pattern>,
+    /// Units that match any of these patterns are never of interest.
+    pub exclude: Vec<Pattern>,
+}
+
+impl Default for UnitFilter {
+    fn default() -> Self {
+        Self {
+            unit_types: vec!["service".to_string()],
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl UnitFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let unit_type = name.rsplit_once('.').map(|(_, unit_type)| unit_type);
+        unit_type.is_some_and(|unit_type| self.unit_types.iter().any(|t| t == unit_type))
+            && (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
 pub struct FilterState<'a> {
     name_filter: Box<dyn FnMut(&str) -> bool + 'a>,
-    state_filter: Box<dyn FnMut(Option<ActiveState>, LoadState, ActiveState) -> bool + 'a>,
+    rules: Rules,
+    silences: Silences,
 }
 
 impl<'a> FilterState<'a> {
     pub fn new() -> Self {
-        let name_filter = |name: &str| name.ends_with(".service");
-        let state_filter = |old_active_state: Option<ActiveState>,
-                            new_load_state: LoadState,
-                            new_active_state: ActiveState| {
-            match (old_active_state, new_load_state, new_active_state.clone()) {
-                (_, LoadState::Error, _) => true,
-                // only trigger if not-found is coupled with anything not inactive
-                (_, LoadState::NotFound, ActiveState::Inactive) => false,
-                (_, LoadState::NotFound, _) => true,
-                (_, LoadState::Unknown(_), _) => true,
-                (_, _, ActiveState::Failed) => true,
-                (_, _, ActiveState::Unknown(_)) => true,
-                // also trigger when a service recovered
-                (Some(ActiveState::Failed), _, _) => new_active_state != ActiveState::Failed,
-                // otherwise false
-                _ => false,
-            }
-        };
+        let unit_filter = UnitFilter::default();
+        let name_filter = move |name: &str| unit_filter.matches(name);
         Self {
             name_filter: Box::new(name_filter),
-            state_filter: Box::new(state_filter),
+            rules: Rules::default(),
+            silences: Silences::default(),
         }
     }
 
+    /// Uses the given rules to decide about the changes of units.
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Uses the given unit filter to select the units of interest.
+    pub fn with_unit_filter(mut self, unit_filter: UnitFilter) -> Self {
+        self.name_filter = Box::new(move |name: &str| unit_filter.matches(name));
+        self
+    }
+
+    /// Uses the given silences to suppress changes of matching units.
+    pub fn with_silences(mut self, silences: Silences) -> Self {
+        self.silences = silences;
+        self
+    }
+
+    pub fn silences(&self) -> &Silences {
+        &self.silences
+    }
+
+    pub fn silences_mut(&mut self) -> &mut Silences {
+        &mut self.silences
+    }
+
+    /// Returns true, if notifications about the unit with the given name are of interest at all.
+    pub fn matches_name(&mut self, name: &str) -> bool {
+        (self.name_filter)(name)
+    }
+
+    /// Returns the severity that the rules assign to the change, if any.
+    pub fn severity(&self, change: &ChangedUnitStatus) -> Option<Severity> {
+        self.rules.evaluate(change).severity
+    }
+
+    /// Returns the severity that the rules assign to the unit in its current state, if any,
+    /// e.g. for notifications that are not caused by a change.
+    pub fn unit_severity(&self, status: &UnitStatus) -> Option<Severity> {
+        self.severity(&ChangedUnitStatus {
+            old: None,
+            new: status.clone(),
+        })
+    }
+
+    /// Returns true, if the unit in its current state should be notified about, e.g. by reminders and escalations,
+    /// i.e. if it matches the unit filter, the rules notify about it and it is not silenced at `now`.
+    pub fn is_notified(&mut self, status: &UnitStatus, now: u64) -> bool {
+        let change = ChangedUnitStatus {
+            old: None,
+            new: status.clone(),
+        };
+        (self.name_filter)(status.name())
+            && self.rules.evaluate(&change).notify
+            && !self.silences.is_silenced(status.name(), now)
+    }
+
+    /// Returns true, if the change is the recovery of a unit from a failure.
+    pub fn is_recovery(&self, status: &ChangedUnitStatus) -> bool {
+        matches!(
+            status.old.as_ref().map(|old| old.active_state()),
+            Some(ActiveState::Failed)
+        ) && status.new.active_state() != &ActiveState::Failed
+    }
+
+    /// Returns true, if the change is of interest according to the unit filter and the rules,
+    /// regardless of any silences.
+    pub fn is_relevant(&mut self, status: &ChangedUnitStatus) -> bool {
+        (self.name_filter)(status.new.name()) && self.rules.evaluate(status).notify
+    }
+
+    /// Returns true, if the change should be notified.
+    /// Relevant changes of units that match an active silence are recorded as suppressed instead.
     pub fn filter_function(&mut self, status: &ChangedUnitStatus) -> bool {
-        let old_active_state = status.clone().old.map(|state| state.active_state().clone());
-        (self.name_filter)(status.new.name())
-            && (self.state_filter)(
-                old_active_state,
-                status.new.load_state().clone(),
-                status.new.active_state().clone(),
-            )
+        if !self.is_relevant(status) {
+            return false;
+        }
+        match self.silences.suppress(&status.new, unix_now()) {
+            Ok(suppressed) => !suppressed,
+            Err(error) => {
+                eprintln!("Error while suppressing change: {:?}", error);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_unit_filter_matches_services() {
+        let filter = UnitFilter::default();
+        assert!(filter.matches("test.service"));
+        assert!(!filter.matches("test.timer"));
+        assert!(!filter.matches("service"));
+    }
+
+    #[test]
+    fn unit_filter_applies_types_include_and_exclude() {
+        let filter = UnitFilter {
+            unit_types: vec!["service".to_string(), "timer".to_string()],
+            include: vec![
+                Pattern::parse("backup*").unwrap(),
+                Pattern::parse("re:systemd-.*").unwrap(),
+            ],
+            exclude: vec![Pattern::parse("systemd-networkd-wait-online.service").unwrap()],
+        };
+        assert!(filter.matches("backup.timer"));
+        assert!(filter.matches("systemd-resolved.service"));
+        assert!(!filter.matches("backup.mount"));
+        assert!(!filter.matches("nginx.service"));
+        assert!(!filter.matches("systemd-networkd-wait-online.service"));
     }
 }
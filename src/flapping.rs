@@ -0,0 +1,235 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    notifications::{NotificationKind, UnitNotification},
+    status::UnitStatus,
+};
+
+/// Keeps the history of relevant transitions per unit to detect units that are flapping,
+/// i.e. that change their state at least `threshold` times within `window`.
+///
+/// A flapping unit produces a single notification when it starts flapping and one once it stabilised,
+/// i.e. when it had no transitions for the duration of the window.
+/// All individual transitions in between are suppressed.
+pub struct FlapDetection {
+    threshold: usize,
+    window: Duration,
+    history: HashMap<String, VecDeque<Instant>>,
+    /// The latest status of every unit that is currently flapping.
+    flapping: HashMap<String, UnitStatus>,
+}
+
+impl FlapDetection {
+    /// Creates a new flap detection. A `threshold` of zero disables the detection.
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            history: HashMap::new(),
+            flapping: HashMap::new(),
+        }
+    }
+
+    /// Records the transitions of the given units and returns the notifications that should be sent for them.
+    pub fn apply(&mut self, states: Vec<UnitStatus>, now: Instant) -> Vec<UnitNotification> {
+        if self.threshold == 0 {
+            return states
+                .into_iter()
+                .map(|status| UnitNotification::new(status, NotificationKind::Changed))
+                .collect();
+        }
+
+        // forget the transitions outside of the window of all units, not only of the changed ones
+        let window = self.window;
+        self.history.retain(|_, history| {
+            while history
+                .front()
+                .is_some_and(|oldest| now.saturating_duration_since(*oldest) > window)
+            {
+                history.pop_front();
+            }
+            !history.is_empty()
+        });
+
+        let mut notifications = Vec::new();
+        for status in states {
+            let history = self.history.entry(status.name().clone()).or_default();
+            history.push_back(now);
+            let transitions = history.len();
+
+            if let Some(latest) = self.flapping.get_mut(status.name()) {
+                // suppress individual transitions while flapping
+                *latest = status;
+            } else if transitions >= self.threshold {
+                self.flapping.insert(status.name().clone(), status.clone());
+                notifications.push(UnitNotification::new(
+                    status,
+                    NotificationKind::Flapping {
+                        transitions,
+                        window_secs: self.window.as_secs(),
+                    },
+                ));
+            } else {
+                notifications.push(UnitNotification::new(status, NotificationKind::Changed));
+            }
+        }
+        notifications
+    }
+
+    /// Returns notifications for all flapping units that had no transitions within the window
+    /// and stops treating them as flapping.
+    pub fn stabilised(&mut self, now: Instant) -> Vec<UnitNotification> {
+        let window = self.window;
+        let history = &self.history;
+        let stable: Vec<String> = self
+            .flapping
+            .keys()
+            .filter(|name| {
+                history
+                    .get(*name)
+                    .and_then(|history| history.back())
+                    .map(|latest| now.saturating_duration_since(*latest) > window)
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+
+        stable
+            .into_iter()
+            .filter_map(|name| {
+                self.history.remove(&name);
+                self.flapping.remove(&name)
+            })
+            .map(|status| UnitNotification::new(status, NotificationKind::Stabilised))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::unit_status;
+
+    #[test]
+    fn disabled_detection_passes_all_transitions() {
+        let mut detection = FlapDetection::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(
+                detection.apply(vec![unit_status("test.service", "failed")], now),
+                vec![UnitNotification::new(
+                    unit_status("test.service", "failed"),
+                    NotificationKind::Changed
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn flapping_unit_is_reported_once_and_suppressed() {
+        let mut detection = FlapDetection::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(
+            detection
+                .apply(vec![unit_status("test.service", "failed")], now)
+                .len(),
+            1
+        );
+        assert_eq!(
+            detection
+                .apply(vec![unit_status("test.service", "active")], now)
+                .len(),
+            1
+        );
+        assert_eq!(
+            detection.apply(vec![unit_status("test.service", "failed")], now),
+            vec![UnitNotification::new(
+                unit_status("test.service", "failed"),
+                NotificationKind::Flapping {
+                    transitions: 3,
+                    window_secs: 60
+                }
+            )]
+        );
+        assert_eq!(
+            detection.apply(vec![unit_status("test.service", "active")], now),
+            vec![]
+        );
+        assert_eq!(detection.stabilised(now), vec![]);
+    }
+
+    #[test]
+    fn flapping_unit_stabilises_after_window() {
+        let mut detection = FlapDetection::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        detection.apply(vec![unit_status("test.service", "failed")], now);
+        detection.apply(vec![unit_status("test.service", "active")], now);
+        detection.apply(
+            vec![unit_status("test.service", "failed")],
+            now + Duration::from_secs(30),
+        );
+        assert_eq!(detection.stabilised(now + Duration::from_secs(60)), vec![]);
+        assert_eq!(
+            detection.stabilised(now + Duration::from_secs(91)),
+            vec![UnitNotification::new(
+                unit_status("test.service", "failed"),
+                NotificationKind::Stabilised
+            )]
+        );
+        // history is reset after stabilising
+        assert_eq!(
+            detection.apply(
+                vec![unit_status("test.service", "active")],
+                now + Duration::from_secs(92)
+            ),
+            vec![UnitNotification::new(
+                unit_status("test.service", "active"),
+                NotificationKind::Changed
+            )]
+        );
+    }
+
+    #[test]
+    fn transitions_outside_window_are_forgotten() {
+        let mut detection = FlapDetection::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        detection.apply(vec![unit_status("test.service", "failed")], now);
+        assert_eq!(
+            detection.apply(
+                vec![unit_status("test.service", "active")],
+                now + Duration::from_secs(61)
+            ),
+            vec![UnitNotification::new(
+                unit_status("test.service", "active"),
+                NotificationKind::Changed
+            )]
+        );
+    }
+
+    #[test]
+    fn history_of_units_without_recent_transitions_is_removed() {
+        let mut detection = FlapDetection::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        detection.apply(vec![unit_status("a.service", "failed")], now);
+        detection.apply(vec![unit_status("b.service", "failed")], now);
+        assert_eq!(detection.history.len(), 2);
+        detection.apply(
+            vec![unit_status("b.service", "active")],
+            now + Duration::from_secs(61),
+        );
+        assert_eq!(
+            detection.history.keys().collect::<Vec<_>>(),
+            vec!["b.service"]
+        );
+        assert_eq!(detection.history["b.service"].len(), 1);
+    }
+}
@@ -4,34 +4,61 @@ SPDX-FileCopyrightText: 2021 localthomas
 SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
+mod aggregation;
+mod check;
 mod config;
+mod config_file;
+mod control;
 mod dbus_systemd;
 mod filter;
+mod flapping;
 mod notifications;
+mod notify_unit;
+mod outbox;
+mod pattern;
+mod quiet_hours;
+mod reconnect;
+mod routing;
+mod rules;
+mod sd_notify;
+mod secret;
+mod silences;
 mod state;
 mod status;
+mod status_report;
+mod test_notification;
 
 use std::{
+    collections::HashMap,
     path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Condvar, Mutex,
     },
     thread, time,
 };
 
-use anyhow::{Context, Result};
-use config::Config;
+use aggregation::Aggregation;
+use anyhow::{anyhow, Context, Result};
+use config::{Config, Subcommand};
+use control::{ControlResponse, ControlSocket};
 use dbus_systemd::dbus::Connection;
 use dbus_systemd::SystemdConnection;
 use filter::FilterState;
-use notifications::NotificationProvider;
-use state::{SystemdState, SystemdStateImpl};
-use status::UnitStatus;
+use flapping::FlapDetection;
+use notifications::{NotificationKind, NotificationProvider, Severity, UnitNotification};
+use outbox::{Outbox, OutboxEntry, PendingNotification};
+use reconnect::{ConnectionLost, Outage, Reconnection};
+use routing::Routes;
+use sd_notify::Notifier;
+use silences::Silences;
+use state::{ChangedUnitStatus, SystemdState, SystemdStateImpl};
+use status::{ActiveState, UnitStatus};
 
 /// Holds the 'global' app internal state of the major sub-components.
-/// This includes the D-Bus connection to systemd, the notification providers and the app-local
-/// mirror of the state of systemd.
+/// This includes the D-Bus connection to systemd, the notification providers, the outbox of notifications
+/// that are not yet delivered, the changes held back for aggregation, the transition history for flap detection
+/// the app-local mirror of the state of systemd and the state of the connection to it.
 struct AppState<'a, C, S>
 where
     C: SystemdConnection,
@@ -41,6 +68,23 @@ where
     conn: C,
     notifications: Arc<Vec<Box<dyn NotificationProvider>>>,
     systemd: S,
+    aggregation: Aggregation,
+    flapping: FlapDetection,
+    /// The interval for repeated notifications about units that stay failed, if enabled.
+    reminder_interval: Option<time::Duration>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// Set while a thread is delivering the due notifications of the outbox, notified when it is done.
+    dispatching: Arc<(Mutex<bool>, Condvar)>,
+    /// Receives commands, e.g. acknowledgements, from CLI subcommands.
+    control: Option<ControlSocket>,
+    /// Selects the notification providers for each notification about a unit.
+    routes: Routes,
+    /// If set, notifications are delivered before returning, e.g. when running only once.
+    synchronous: bool,
+    /// The number of units of interest and how many of them are failed, as of the last poll.
+    monitored_units: (usize, usize),
+    /// Schedules the attempts to reconnect after the connection to systemd was lost.
+    reconnection: Reconnection,
 }
 
 impl<'a, C, S> AppState<'a, C, S>
@@ -51,69 +95,439 @@ where
     /// Poll the system bus for new changes on the systemd daemon.
     /// The response includes all units of the current state and this function only returns the filtered
     /// unit status and only if they changed from the previous call to this function (hence the `&mut`).
-    fn poll_for_new_systemd_state(&mut self) -> Result<Vec<UnitStatus>> {
-        let unit_status = self.conn.list_units().context("could not list units")?;
+    /// Changes are held back until the aggregation window has elapsed and changes of flapping units
+    /// are replaced by notifications about the flapping.
+    /// Recoveries of failures that were escalated are additionally sent to the providers of the escalation tiers,
+    /// so are escalated failures of units that are no longer listed.
+    fn poll_for_new_systemd_state(&mut self) -> Result<Vec<UnitNotification>> {
+        let unit_status = self
+            .conn
+            .list_units()
+            .context("could not list units")
+            .context(ConnectionLost)?;
         let unit_status: Vec<UnitStatus> = unit_status.into_iter().map(UnitStatus::from).collect();
-        let changes = self.systemd.apply_new_status(unit_status);
-        let filtered: Vec<UnitStatus> = changes
+        let monitored: Vec<&UnitStatus> = unit_status
             .iter()
-            .cloned()
+            .filter(|status| self.filter.matches_name(status.name()))
+            .collect();
+        self.monitored_units = (
+            monitored.len(),
+            monitored
+                .iter()
+                .filter(|status| status.active_state() == &ActiveState::Failed)
+                .count(),
+        );
+        let forgotten = self.systemd.forget_missing(&unit_status);
+        let changes = self.systemd.apply_new_status(unit_status);
+        let now = time::Instant::now();
+        self.aggregation
+            .add(changes, now)
+            .context("could not hold changes for aggregation")?;
+        let changes = self
+            .aggregation
+            .take_if_elapsed(now)
+            .context("could not take aggregated changes")?;
+        let mut notifications = self.process_changes(changes, now);
+        for status in forgotten {
+            for provider in self.systemd.take_resolved_escalations(status.name()) {
+                notifications.push(UnitNotification::targeted(
+                    status.clone(),
+                    NotificationKind::Forgotten,
+                    &provider,
+                ));
+            }
+        }
+        Ok(notifications)
+    }
+
+    /// Returns the notifications for the given changes that are of interest.
+    /// Changes of flapping units are replaced by notifications about the flapping and
+    /// recoveries of failures that were escalated are additionally sent to the providers of the escalation tiers.
+    fn process_changes(
+        &mut self,
+        changes: Vec<ChangedUnitStatus>,
+        now: time::Instant,
+    ) -> Vec<UnitNotification> {
+        let filtered: Vec<ChangedUnitStatus> = changes
+            .into_iter()
             .filter(|status| self.filter.filter_function(status))
-            .map(|changed_state| changed_state.new)
             .collect();
-        Ok(filtered)
+
+        let filter = &self.filter;
+        let severities: HashMap<String, Option<Severity>> = filtered
+            .iter()
+            .map(|change| (change.new.name().clone(), filter.severity(change)))
+            .collect();
+
+        let mut escalated_recoveries = Vec::new();
+        for change in filtered.iter().filter(|change| filter.is_recovery(change)) {
+            for provider in self.systemd.take_resolved_escalations(change.new.name()) {
+                escalated_recoveries.push(UnitNotification::targeted(
+                    change.new.clone(),
+                    NotificationKind::Changed,
+                    &provider,
+                ));
+            }
+        }
+
+        let filtered = filtered.into_iter().map(|change| change.new).collect();
+        let mut notifications = self.flapping.apply(filtered, now);
+        notifications.append(&mut self.flapping.stabilised(now));
+        notifications.append(&mut escalated_recoveries);
+        notifications
+            .into_iter()
+            .map(|notification| {
+                let severity = match severities.get(notification.status.name()) {
+                    Some(severity) => *severity,
+                    None => filter.unit_severity(&notification.status),
+                };
+                notification.with_severity(severity)
+            })
+            .collect()
+    }
+
+    /// Queues notifications for all changes that are held back for aggregation, regardless of the window,
+    /// and tries to deliver them before returning, e.g. when the program terminates.
+    /// Notifications that could not be delivered stay in the outbox.
+    fn flush_aggregation(&mut self) -> Result<()> {
+        let changes = self
+            .aggregation
+            .take()
+            .context("could not take aggregated changes")?;
+        let notifications = self.process_changes(changes, time::Instant::now());
+        if notifications.is_empty() {
+            return Ok(());
+        }
+        self.enqueue(PendingNotification::Units(notifications))
+            .context("could not queue aggregated notifications")?;
+        self.dispatch_outbox(true);
+        Ok(())
+    }
+
+    /// Returns reminder notifications for all units that stay failed and were not notified about
+    /// within the reminder interval.
+    fn due_reminders(&mut self) -> Vec<UnitNotification> {
+        let interval = match self.reminder_interval {
+            Some(interval) => interval,
+            None => return Vec::new(),
+        };
+        let now = state::unix_now();
+        let reminders = self.systemd.due_reminders(interval, now);
+        reminders
+            .into_iter()
+            .filter_map(|(status, failure)| {
+                if !self.filter.is_notified(&status, now) {
+                    return None;
+                }
+                let severity = self.filter.unit_severity(&status);
+                Some(
+                    UnitNotification::new(
+                        status,
+                        NotificationKind::Reminder {
+                            failed_since: failure.since,
+                        },
+                    )
+                    .with_severity(severity),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns escalation notifications for all units that are failed for longer than the delay of a
+    /// notification provider of an escalation tier and were not yet escalated to it.
+    fn due_escalations(&mut self) -> Vec<UnitNotification> {
+        let now = state::unix_now();
+        let mut notifications = Vec::new();
+        for provider in &*self.notifications {
+            let delay = match provider.escalate_after() {
+                Some(delay) => delay,
+                None => continue,
+            };
+            // only failures that are notified now are marked as escalated, e.g. silenced ones are escalated later
+            let filter = &mut self.filter;
+            let mut is_escalated = |status: &UnitStatus| filter.is_notified(status, now);
+            for (status, failure) in
+                self.systemd
+                    .due_escalations(provider.name(), delay, now, &mut is_escalated)
+            {
+                let severity = self.filter.unit_severity(&status);
+                notifications.push(
+                    UnitNotification::targeted(
+                        status,
+                        NotificationKind::Escalation {
+                            failed_since: failure.since,
+                        },
+                        provider.name(),
+                    )
+                    .with_severity(severity),
+                );
+            }
+        }
+        notifications
+    }
+
+    /// Removes all expired silences and returns notifications about the changes they suppressed.
+    fn expired_silences(&mut self) -> Result<Vec<UnitNotification>> {
+        let expired = self
+            .filter
+            .silences_mut()
+            .expire(state::unix_now())
+            .context("could not remove expired silences")?;
+        let filter = &self.filter;
+        Ok(expired
+            .into_iter()
+            .flat_map(|silence| {
+                let comment = silence.comment;
+                silence.suppressed.into_iter().map(move |suppressed| {
+                    let severity = filter.unit_severity(&suppressed.status);
+                    UnitNotification::new(
+                        suppressed.status,
+                        NotificationKind::SilenceEnded {
+                            comment: comment.clone(),
+                            changes: suppressed.count,
+                        },
+                    )
+                    .with_severity(severity)
+                })
+            })
+            .collect())
+    }
+
+    /// Returns a short description of the monitored units for the status of the service.
+    fn status_line(&self) -> String {
+        let (monitored, failed) = self.monitored_units;
+        format!("monitoring {} units, {} failed", monitored, failed)
+    }
+
+    /// Applies a new configuration to the filter, the notification providers, the routes and the reminders.
+    /// All other state, e.g. the last known state of systemd, the silences and the outbox, is kept.
+    /// All other settings are only applied on start (see `Config::restart_required`).
+    /// If the configuration can not be applied, the previous configuration stays active.
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        let notifications = notifications::create_notifications(config)
+            .context("could not create notifications provider")?;
+        let silences = std::mem::take(self.filter.silences_mut());
+        self.filter = create_filter(config, silences);
+        self.notifications = Arc::new(notifications);
+        self.routes = config.routes.clone();
+        self.reminder_interval = config.reminder_interval;
+        Ok(())
+    }
+
+    /// Apply all commands that were received on the control socket since the last call and send back the results.
+    fn handle_control_commands(&mut self) {
+        let control = match &self.control {
+            Some(control) => control,
+            None => return,
+        };
+        while let Some((command, reply)) = control.try_recv() {
+            let mut outbox = self.outbox.lock().expect("outbox mutex is poisoned");
+            let response =
+                match command.apply(&mut self.systemd, self.filter.silences_mut(), &mut outbox) {
+                    Ok(message) => {
+                        println!("{}", message);
+                        ControlResponse::Ok(message)
+                    }
+                    Err(error) => ControlResponse::Error(format!("{:#}", error)),
+                };
+            // the client might have already disconnected, which is not an error of the daemon
+            let _ = reply.send(response);
+        }
     }
 
-    /// Execute notifications for the given status array that holds all relevant changes of units
+    /// Queue notifications for the given status array that holds all relevant changes of units
     /// which the user is notified by all notification providers.
     ///
-    /// Any errors during initial notification of the changed services are then also broadcasted by
-    /// executing the error-notification of all notification providers.
-    /// An error on one notification provider is send to all notification providers' error-notifies.
-    fn notify(&self, status: Vec<UnitStatus>) {
-        for service in &status {
+    /// The notifications are stored in the outbox for each notification provider and delivered in a
+    /// separate thread, so that failed deliveries can be retried later on.
+    fn notify(&self, notifications: Vec<UnitNotification>) -> Result<()> {
+        if notifications.is_empty() {
+            return Ok(());
+        }
+        for notification in &notifications {
             println!(
                 "{} has changed states. Executing webhooks...",
-                service.name()
+                notification.status.name()
             );
         }
+        self.enqueue(PendingNotification::Units(notifications))
+            .context("could not queue notification")?;
+        self.dispatch_outbox(self.synchronous);
+        Ok(())
+    }
+
+    /// Queue notifications for the start of the application, i.e. when it starts the main work and is ready.
+    fn notify_start(&self) -> Result<()> {
+        self.enqueue(PendingNotification::Start)
+            .context("could not queue start-notification")?;
+        self.dispatch_outbox(self.synchronous);
+        Ok(())
+    }
 
-        // execute for each notification provider the provided function that executes the notification
-        // in a separate thread to prevent blocking the process in case of errors
-        for notification in &*self.notifications {
-            let func = notification.execute(status.clone());
+    /// Queue a notification about the restored connection to systemd, which was lost at the given Unix timestamp.
+    fn notify_reconnected(&self, lost_since: u64) -> Result<()> {
+        self.enqueue(PendingNotification::Reconnected { lost_since })
+            .context("could not queue reconnected-notification")?;
+        self.dispatch_outbox(self.synchronous);
+        Ok(())
+    }
 
-            // clone the atomic reference for each thread that is spawned
-            let notifications = self.notifications.clone();
-            std::thread::spawn(move || {
-                // if an error occurs during the execution of the notification function,
-                // execute a notification for the error itself
-                if let Err(error) = func() {
-                    eprintln!("Error during notification: {:?}", error);
-                    Self::notify_error_internal(notifications, &error, false);
+    /// Store the given notification in the outbox for every notification provider it is relevant for.
+    /// During the quiet hours of a provider, unit notifications below its bypass severity are held back.
+    fn enqueue(&self, notification: PendingNotification) -> Result<()> {
+        let now = state::unix_now();
+        let mut outbox = self.outbox.lock().expect("outbox mutex is poisoned");
+        for provider in &*self.notifications {
+            let notification = match notification.for_provider(provider.as_ref(), &self.routes) {
+                Some(notification) => notification,
+                None => continue,
+            };
+            let quiet_hours = provider
+                .quiet_hours()
+                .and_then(|quiet_hours| quiet_hours.end(now).map(|end| (quiet_hours, end)));
+            match (notification, quiet_hours) {
+                (PendingNotification::Units(notifications), Some((quiet_hours, end))) => {
+                    let (immediate, held): (Vec<UnitNotification>, Vec<UnitNotification>) =
+                        notifications.into_iter().partition(|notification| {
+                            notification.severity() >= quiet_hours.bypass_severity()
+                        });
+                    if !immediate.is_empty() {
+                        outbox.push(provider.name(), PendingNotification::Units(immediate))?;
+                    }
+                    if !held.is_empty() {
+                        outbox.hold(provider.name(), held, end)?;
+                    }
                 }
-            });
+                (notification, _) => outbox.push(provider.name(), notification)?,
+            }
         }
+        Ok(())
     }
 
-    /// Execute notifications for the start of the application, i.e. when it starts the main work and is ready.
-    fn notify_start(&self) {
-        // execute for each notification provider the provided function that executes the notification
-        // in a separate thread to prevent blocking the process in case of errors
-        for notification in &*self.notifications {
-            let func = notification.execute_start();
+    /// Deliver all due notifications of the outbox in a separate thread.
+    /// Only one thread delivers notifications at a time, i.e. if a delivery is still running, this call does nothing.
+    ///
+    /// If `synchronous` is set to true, the call will instead wait for a running delivery to finish,
+    /// deliver the notifications that are still due and block until the delivery is done.
+    fn dispatch_outbox(&self, synchronous: bool) {
+        {
+            let (running, finished) = &*self.dispatching;
+            let mut running = running.lock().expect("dispatching mutex is poisoned");
+            if *running {
+                if !synchronous {
+                    return;
+                }
+                running = finished
+                    .wait_while(running, |running| *running)
+                    .expect("dispatching mutex is poisoned");
+            }
+            if !self
+                .outbox
+                .lock()
+                .expect("outbox mutex is poisoned")
+                .has_due(state::unix_now())
+            {
+                return;
+            }
+            *running = true;
+        }
 
-            // clone the atomic reference for each thread that is spawned
-            let notifications = self.notifications.clone();
-            std::thread::spawn(move || {
-                // if an error occurs during the execution of the notification function,
-                // execute a notification for the error itself
-                if let Err(error) = func() {
-                    eprintln!("Error during start-notification: {:?}", error);
-                    Self::notify_error_internal(notifications, &error, false);
+        let outbox = self.outbox.clone();
+        let notifications = self.notifications.clone();
+        let dispatching = self.dispatching.clone();
+        let handle = std::thread::spawn(move || {
+            if let Err(error) = Self::dispatch_outbox_internal(&outbox, notifications.clone()) {
+                eprintln!("Error during delivery of notifications: {:?}", error);
+                Self::notify_error_internal(notifications, &error, false);
+            }
+            let (running, finished) = &*dispatching;
+            *running.lock().expect("dispatching mutex is poisoned") = false;
+            finished.notify_all();
+        });
+
+        if synchronous {
+            if let Err(err) = handle.join() {
+                eprintln!("could not join thread for outbox delivery: {:#?}", err);
+            }
+        }
+    }
+
+    /// Delivers all due notifications of the outbox and records the results.
+    /// Notifications that exceeded the maximum age are dropped and reported as error-notification.
+    fn dispatch_outbox_internal(
+        outbox: &Mutex<Outbox>,
+        notifications: Arc<Vec<Box<dyn NotificationProvider>>>,
+    ) -> Result<()> {
+        let now = state::unix_now();
+        let (expired, due) = {
+            let mut outbox = outbox.lock().expect("outbox mutex is poisoned");
+            (outbox.expire(now)?, outbox.due(now))
+        };
+
+        for entry in expired {
+            let error = anyhow!(
+                "dropped notification for provider '{}' after {} failed attempts (last error: {})",
+                entry.provider,
+                entry.attempts,
+                entry.last_error.unwrap_or_default()
+            );
+            eprintln!("{}", error);
+            Self::notify_error_internal(notifications.clone(), &error, false);
+        }
+
+        // deliver the entries of each notification provider in a separate thread,
+        // but keep the order of the entries for a single provider
+        let mut thread_handles = Vec::new();
+        for index in 0..notifications.len() {
+            let provider_name = notifications[index].name();
+            let entries: Vec<OutboxEntry> = due
+                .iter()
+                .filter(|entry| entry.provider == provider_name)
+                .cloned()
+                .collect();
+            let notifications = notifications.clone();
+            thread_handles.push(std::thread::spawn(move || {
+                outbox::deliver(notifications[index].as_ref(), entries)
+            }));
+        }
+
+        // entries for providers that do not exist any more can never be delivered
+        let orphaned = due.iter().filter(|entry| {
+            !notifications
+                .iter()
+                .any(|notification| notification.name() == entry.provider)
+        });
+
+        let mut outbox = outbox.lock().expect("outbox mutex is poisoned");
+        for entry in orphaned {
+            eprintln!(
+                "Dropping notification for unknown provider '{}'",
+                entry.provider
+            );
+            outbox.discard(entry.id, "unknown notification provider".to_string())?;
+        }
+        for thread_handle in thread_handles {
+            let results = match thread_handle.join() {
+                Ok(results) => results,
+                Err(err) => {
+                    eprintln!(
+                        "could not join thread for notification provider: {:#?}",
+                        err
+                    );
+                    continue;
                 }
-            });
+            };
+            for (id, delivered_parts, result) in results {
+                match result {
+                    Ok(()) => outbox.complete(id)?,
+                    Err(error) => {
+                        eprintln!("Error during notification (will be retried): {:?}", error);
+                        outbox.reschedule(id, now, format!("{:#}", error), delivered_parts)?;
+                    }
+                }
+            }
         }
+        Ok(())
     }
 
     /// Execute an error notification for program-internal errors.
@@ -166,19 +580,61 @@ where
 ///
 /// Not usable for unit tests, unless the presence of systemd can be verified.
 fn initialize<'a>(config: &Config) -> Result<AppState<'a, Connection, SystemdStateImpl>> {
-    let filter = FilterState::new();
-    let conn = Connection::new().context("could not create connection")?;
+    let state_file_path = Path::new(&config.state_file_path).to_path_buf();
+    let filter = create_filter(
+        config,
+        Silences::new(Silences::file_path_for_state_file(&state_file_path)),
+    );
+    let conn = Connection::new(config.dbus_timeout).context("could not create connection")?;
     let notifications = notifications::create_notifications(config)
         .context("could not create notifications provider")?;
-    let systemd = SystemdStateImpl::new(Path::new(&config.state_file_path).to_path_buf());
+    let outbox = Outbox::new(
+        Outbox::file_path_for_state_file(&state_file_path),
+        config.outbox_max_age,
+    );
+    let systemd = SystemdStateImpl::new(state_file_path.clone());
+    // when running only once, commands are applied to the state files directly
+    let control = if config.once {
+        None
+    } else {
+        Some(
+            ControlSocket::bind(Path::new(&config.control_socket_path))
+                .context("could not create control socket")?,
+        )
+    };
+    // there is no later iteration that could report aggregated changes
+    let aggregation_window = if config.once {
+        time::Duration::ZERO
+    } else {
+        config.aggregation_window
+    };
     Ok(AppState {
         filter,
         conn,
         notifications: Arc::new(notifications),
         systemd,
+        aggregation: Aggregation::new(aggregation_window)
+            .with_file_path(Aggregation::file_path_for_state_file(&state_file_path)),
+        flapping: FlapDetection::new(config.flap_threshold, config.flap_window),
+        reminder_interval: config.reminder_interval,
+        outbox: Arc::new(Mutex::new(outbox)),
+        dispatching: Arc::new((Mutex::new(false), Condvar::new())),
+        control,
+        routes: config.routes.clone(),
+        synchronous: config.once,
+        monitored_units: (0, 0),
+        reconnection: Reconnection::new(config.reconnect_budget),
     })
 }
 
+/// Creates the filter for the unit filter and rules of the configuration.
+fn create_filter<'a>(config: &Config, silences: Silences) -> FilterState<'a> {
+    FilterState::new()
+        .with_unit_filter(config.unit_filter.clone())
+        .with_rules(config.rules.clone())
+        .with_silences(silences)
+}
+
 fn main() -> Result<()> {
     let termination = Arc::new(AtomicBool::new(false));
     // Make sure double CTRL+C and similar kills
@@ -191,6 +647,9 @@ fn main() -> Result<()> {
         // first arm and then terminate ‒ all in the first round.
         signal_hook::flag::register(*sig, Arc::clone(&termination))?;
     }
+    // SIGHUP only requests a reload of the configuration, which is done by the main loop
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))?;
 
     let config = Config::new().context("could not create configuration")?;
 
@@ -200,14 +659,46 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(command) = &config.command {
+        return run_subcommand(&config, command);
+    }
+
     let mut state = initialize(&config).context("could not initialize state")?;
 
+    if config.once {
+        if run_once(&mut state)? {
+            // failed units are reported with a distinct exit code, e.g. for cron jobs or CI
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+
+    // the service manager is optional, so the program continues without it
+    let notifier = Notifier::from_env().unwrap_or_else(|error| {
+        eprintln!(
+            "{:?}",
+            error.context("could not connect to the service manager, continuing without it")
+        );
+        Notifier::default()
+    });
+    notifier.ready();
+
     if !config.disable_start_notification {
-        state.notify_start();
+        state
+            .notify_start()
+            .context("could not send start notification")?;
     }
 
     // if the error_boundary function produces an error, it can be send as notification
-    if let Err(err) = error_boundary(&mut state, termination) {
+    let result = error_boundary(&mut state, &config, termination, reload, &notifier);
+    notifier.stopping();
+    // changes that are held back for aggregation are part of the state file already
+    if let Err(err) = state.flush_aggregation() {
+        let err = err.context("could not notify about aggregated changes");
+        eprintln!("{:?}", err);
+        state.notify_error(&err, true);
+    }
+    if let Err(err) = result {
         let err = err.context("error during main execution");
         eprintln!("{}", err);
         // Note: wait for the sending of errors, as the program terminates right after this execution
@@ -216,19 +707,164 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Executes the main loop exactly once and delivers all notifications before returning.
+/// Returns true, if any unit of interest is failed afterwards.
+fn run_once(state: &mut AppState<'_, Connection, SystemdStateImpl>) -> Result<bool> {
+    if let Err(err) = main_loop(state) {
+        let err = err.context("error during one-shot execution");
+        state.notify_error(&err, true);
+        return Err(err);
+    }
+    let filter = &mut state.filter;
+    let failed: Vec<String> = state
+        .systemd
+        .failures()
+        .into_iter()
+        .map(|(status, _)| status.name().clone())
+        .filter(|name| filter.matches_name(name))
+        .collect();
+    for name in &failed {
+        println!("{} is failed", name);
+    }
+    Ok(!failed.is_empty())
+}
+
+/// Executes a subcommand of the program instead of running the daemon.
+fn run_subcommand(config: &Config, command: &Subcommand) -> Result<()> {
+    match command {
+        Subcommand::Control(command) => control::run(
+            Path::new(&config.control_socket_path),
+            Path::new(&config.state_file_path).to_path_buf(),
+            config.outbox_max_age,
+            command.clone(),
+        ),
+        Subcommand::CheckConfig { connectivity } => check::run(config, *connectivity),
+        Subcommand::TestNotification(unit) => test_notification::run(config, unit),
+        Subcommand::Status { live, json } => status_report::run(config, *live, *json),
+        Subcommand::NotifyUnit { unit } => notify_unit::run(config, unit),
+    }
+}
+
 /// This function is similar to a main function, but requires the app's state for execution.
 /// In practice, it should be called from the [`main`] function and resulting errors should be handled by creating a notification.
-fn error_boundary<C, S>(state: &mut AppState<'_, C, S>, termination: Arc<AtomicBool>) -> Result<()>
+/// Changes of the number of monitored and failed units are reported as status to systemd.
+fn error_boundary<C, S>(
+    state: &mut AppState<'_, C, S>,
+    config: &Config,
+    termination: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+    notifier: &Notifier,
+) -> Result<()>
 where
     C: SystemdConnection,
     S: SystemdState,
 {
-    looping(time::Duration::from_millis(2_000), termination, move || {
-        main_loop(state).context("error during main loop")
+    let mut last_status = String::new();
+    looping(config.poll_interval, termination, notifier, move || {
+        if reload.swap(false, Ordering::SeqCst) {
+            notifier.reloading();
+            reload_configuration(state, config);
+            notifier.ready();
+        }
+        main_loop_with_reconnect(state, time::Instant::now()).context("error during main loop")?;
+        let status = state.status_line();
+        if status != last_status {
+            notifier.status(&status);
+            last_status = status;
+        }
+        Ok(())
     })?;
     Ok(())
 }
 
+/// Reads the configuration again and applies it to the app's state.
+/// Only the configuration file (and files referenced by it) can change, as the command line arguments and the
+/// environment of the process are the same as on start.
+/// Settings that are only applied on start must not differ from the configuration of the start (`start_config`).
+/// A failed reload is reported as error-notification and the previous configuration stays active.
+fn reload_configuration<C, S>(state: &mut AppState<'_, C, S>, start_config: &Config)
+where
+    C: SystemdConnection,
+    S: SystemdState,
+{
+    let result = Config::new()
+        .context("could not create configuration")
+        .and_then(|config| {
+            let changed = start_config.restart_required(&config);
+            if !changed.is_empty() {
+                return Err(anyhow!(
+                    "changing {} requires a restart",
+                    changed.join(", ")
+                ));
+            }
+            state.reload(&config)
+        });
+    match result {
+        Ok(()) => println!("Reloaded configuration"),
+        Err(error) => {
+            let error = error.context("could not reload configuration, keeping the previous one");
+            eprintln!("{:?}", error);
+            state.notify_error(&error, false);
+        }
+    }
+}
+
+/// Executes the main loop, unless the connection to systemd is lost and the next attempt to reconnect is not yet due.
+/// Errors of the connection are only returned, once the connection is lost for longer than the reconnect budget.
+/// The loss of the connection is reported as error-notification and its restoration as reconnected-notification.
+fn main_loop_with_reconnect<C, S>(state: &mut AppState<'_, C, S>, now: time::Instant) -> Result<()>
+where
+    C: SystemdConnection,
+    S: SystemdState,
+{
+    if !state.reconnection.is_due(now) {
+        // commands and undelivered notifications do not depend on the connection to systemd
+        state.handle_control_commands();
+        state.dispatch_outbox(state.synchronous);
+        return Ok(());
+    }
+    let result = if state.reconnection.is_lost() {
+        state
+            .conn
+            .reconnect()
+            .context("could not reconnect")
+            .context(ConnectionLost)
+            .and_then(|_| main_loop(state))
+    } else {
+        main_loop(state)
+    };
+    match result {
+        Ok(()) => {
+            if let Some(lost_since) = state.reconnection.succeeded() {
+                println!("Restored the connection to systemd");
+                state.notify_reconnected(lost_since)?;
+            }
+            Ok(())
+        }
+        Err(error) if ConnectionLost::is_cause_of(&error) => {
+            match state.reconnection.failed(now, state::unix_now()) {
+                Outage::Started => {
+                    let error = error.context(format!(
+                        "retrying with backoff for up to {:?}",
+                        state.reconnection.budget()
+                    ));
+                    eprintln!("{:?}", error);
+                    state.notify_error(&error, false);
+                    Ok(())
+                }
+                Outage::Ongoing => {
+                    eprintln!("Could not restore the connection to systemd: {:#}", error);
+                    Ok(())
+                }
+                Outage::Persistent => {
+                    Err(error.context("the connection to systemd could not be restored"))
+                }
+            }
+        }
+        Err(error) => Err(error),
+    }
+}
+
 /// Execute the typical workload for this daemon program for one iteration.
 /// Designed to be periodically executed.
 fn main_loop<C, S>(state: &mut AppState<'_, C, S>) -> Result<()>
@@ -236,28 +872,59 @@ where
     C: SystemdConnection,
     S: SystemdState,
 {
-    let status = state
+    state.handle_control_commands();
+    let mut notifications = state
         .poll_for_new_systemd_state()
         .context("could not poll for new systemd state")?;
-    state.notify(status);
+    notifications.append(&mut state.due_reminders());
+    notifications.append(&mut state.due_escalations());
+    notifications.append(&mut state.expired_silences()?);
+    state
+        .notify(notifications)
+        .context("could not notify about new systemd state")?;
+    // retry any notifications that could not be delivered previously
+    state.dispatch_outbox(state.synchronous);
     Ok(())
 }
 
 /// Provides a timed loop, where each iteration is executed in the specified interval.
 /// If the execution of the function in an iteration is taking longer than the specified interval,
-/// the next iteration follows promptly.
+/// the next iteration follows promptly and the overrun is reported.
+/// The watchdog of systemd is kept alive after each successful iteration and, if its interval is shorter,
+/// while waiting for the next one, so that a hanging iteration lets the watchdog expire.
 ///
 /// The endless loop is stopped on receiving an error from the iteration function.
 fn looping<T: FnMut() -> Result<()>>(
     interval: time::Duration,
     termination: Arc<AtomicBool>,
+    notifier: &Notifier,
     mut function: T,
 ) -> Result<()> {
     while !termination.load(Ordering::Relaxed) {
         let start = time::Instant::now();
         function()?;
+        notifier.watchdog();
+        let elapsed = start.elapsed();
+        if elapsed > interval {
+            eprintln!(
+                "Iteration took {:?}, which is longer than the interval of {:?}",
+                elapsed, interval
+            );
+        }
         // measure time and then sleep exact so long that the interval is met
-        thread::sleep(interval.saturating_sub(start.elapsed()));
+        loop {
+            let remaining = interval.saturating_sub(start.elapsed());
+            match notifier.watchdog_interval() {
+                Some(watchdog_interval) if watchdog_interval < remaining => {
+                    thread::sleep(watchdog_interval);
+                    notifier.watchdog();
+                }
+                _ => {
+                    thread::sleep(remaining);
+                    break;
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -266,19 +933,37 @@ fn looping<T: FnMut() -> Result<()>>(
 mod tests {
     use anyhow::anyhow;
     use dbus_systemd::{dbus::UnitStatusRaw, tests::MockupSystemdConnection};
+    use notifications::tests::MockupNotificationProvider;
 
-    use crate::state::tests::MockupSystemdState;
+    use crate::state::tests::{raw_unit_status, temp_file_path, unit_status, MockupSystemdState};
 
     use super::*;
 
-    #[test]
-    fn main_loop_return_on_error() {
-        let mut state = AppState {
+    fn mockup_state() -> AppState<'static, MockupSystemdConnection, MockupSystemdState> {
+        AppState {
             filter: FilterState::new(),
             conn: MockupSystemdConnection::new(),
             notifications: Arc::new(vec![]),
             systemd: MockupSystemdState::new(),
-        };
+            aggregation: Aggregation::new(time::Duration::ZERO),
+            flapping: FlapDetection::new(0, time::Duration::ZERO),
+            reminder_interval: None,
+            outbox: Arc::new(Mutex::new(Outbox::new(
+                temp_file_path(),
+                time::Duration::from_secs(60),
+            ))),
+            dispatching: Arc::new((Mutex::new(false), Condvar::new())),
+            control: None,
+            routes: Routes::default(),
+            synchronous: false,
+            monitored_units: (0, 0),
+            reconnection: Reconnection::new(time::Duration::from_secs(60)),
+        }
+    }
+
+    #[test]
+    fn main_loop_return_on_error() {
+        let mut state = mockup_state();
         state.conn.error = true;
         let result = main_loop(&mut state);
         assert_eq!(state.systemd.last_state, None);
@@ -289,14 +974,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn lost_connection_is_retried_within_budget() {
+        let mut state = mockup_state();
+        state.conn.error = true;
+        let start = time::Instant::now();
+        main_loop_with_reconnect(&mut state, start).expect("should retry within the budget");
+        assert!(state.reconnection.is_lost());
+        // the next attempt is not yet due
+        main_loop_with_reconnect(&mut state, start).unwrap();
+        assert_eq!(state.conn.reconnects, 0);
+
+        state.conn.error = false;
+        main_loop_with_reconnect(&mut state, start + time::Duration::from_secs(1)).unwrap();
+        assert_eq!(state.conn.reconnects, 1);
+        assert!(!state.reconnection.is_lost());
+
+        state.conn.error = true;
+        main_loop_with_reconnect(&mut state, start).unwrap();
+        let result = main_loop_with_reconnect(&mut state, start + time::Duration::from_secs(60));
+        assert!(result.is_err());
+    }
+
     #[test]
     fn main_loop_new_empty_status_from_connection() {
-        let mut state = AppState {
-            filter: FilterState::new(),
-            conn: MockupSystemdConnection::new(),
-            notifications: Arc::new(vec![]),
-            systemd: MockupSystemdState::new(),
-        };
+        let mut state = mockup_state();
         state.conn.units = vec![];
         assert_eq!(state.systemd.last_state, None);
         main_loop(&mut state).expect("should not throw error");
@@ -314,12 +1016,7 @@ mod tests {
             sub_state: String::from(TEST),
             following_unit: String::from(TEST),
         };
-        let mut state = AppState {
-            filter: FilterState::new(),
-            conn: MockupSystemdConnection::new(),
-            notifications: Arc::new(vec![]),
-            systemd: MockupSystemdState::new(),
-        };
+        let mut state = mockup_state();
         state.conn.units = vec![raw_unit.clone()];
         assert_eq!(state.systemd.last_state, None);
         main_loop(&mut state).expect("should not throw error");
@@ -327,5 +1024,172 @@ mod tests {
             state.systemd.last_state,
             Some(vec![UnitStatus::from(raw_unit.clone())])
         );
+        // units without a type of interest are not monitored
+        assert_eq!(state.status_line(), "monitoring 0 units, 0 failed");
+        state.conn.units = vec![raw_unit_status("test.service", "failed")];
+        main_loop(&mut state).expect("should not throw error");
+        assert_eq!(state.status_line(), "monitoring 1 units, 1 failed");
+    }
+
+    #[test]
+    fn notify_delivers_through_outbox() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(provider)]);
+        let status = unit_status("test.service", "failed");
+        let notification = UnitNotification::new(status, notifications::NotificationKind::Changed);
+        state
+            .enqueue(PendingNotification::Units(vec![notification.clone()]))
+            .unwrap();
+        state.dispatch_outbox(true);
+        assert_eq!(*sent.lock().unwrap(), vec![vec![notification]]);
+        assert!(!state.outbox.lock().unwrap().has_due(u64::MAX));
+    }
+
+    #[test]
+    fn synchronous_main_loop_delivers_before_returning() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(provider)]);
+        state.synchronous = true;
+        state.conn.units = vec![raw_unit_status("test.service", "failed")];
+        main_loop(&mut state).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        assert!(!*state.dispatching.0.lock().unwrap());
+    }
+
+    #[test]
+    fn synchronous_dispatch_waits_for_running_dispatch() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(provider)]);
+        let notification = UnitNotification::new(
+            unit_status("test.service", "failed"),
+            notifications::NotificationKind::Changed,
+        );
+        state
+            .enqueue(PendingNotification::Units(vec![notification]))
+            .unwrap();
+        *state.dispatching.0.lock().unwrap() = true;
+        let dispatching = state.dispatching.clone();
+        let running = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            *dispatching.0.lock().unwrap() = false;
+            dispatching.1.notify_all();
+        });
+        // an asynchronous dispatch leaves the delivery to the running dispatch
+        state.dispatch_outbox(false);
+        assert!(sent.lock().unwrap().is_empty());
+        state.dispatch_outbox(true);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn held_changes_are_delivered_on_termination() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(provider)]);
+        state.aggregation = Aggregation::new(time::Duration::from_secs(60));
+        state.conn.units = vec![raw_unit_status("test.service", "failed")];
+        main_loop(&mut state).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        state.flush_aggregation().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        // nothing is held back any more
+        state.flush_aggregation().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    fn test_config() -> Config {
+        Config {
+            providers: vec![],
+            state_file_path: String::new(),
+            control_socket_path: String::new(),
+            outbox_max_age: time::Duration::from_secs(60),
+            unit_filter: filter::UnitFilter::default(),
+            rules: rules::Rules::default(),
+            routes: Routes::default(),
+            aggregation_window: time::Duration::ZERO,
+            flap_threshold: 0,
+            flap_window: time::Duration::ZERO,
+            reminder_interval: Some(time::Duration::from_secs(60)),
+            poll_interval: time::Duration::from_millis(2_000),
+            dbus_timeout: time::Duration::from_millis(10_000),
+            reconnect_budget: time::Duration::from_secs(600),
+            about: false,
+            disable_start_notification: false,
+            once: false,
+            command: None,
+        }
+    }
+
+    #[test]
+    fn reload_replaces_providers_and_keeps_state() {
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(MockupNotificationProvider::new())]);
+        state.conn.units = vec![];
+        main_loop(&mut state).unwrap();
+        state
+            .filter
+            .silences_mut()
+            .add("*".to_string(), None, 0, u64::MAX, "".to_string())
+            .unwrap();
+
+        let mut config = test_config();
+        // without any notification provider, the previous configuration stays active
+        assert!(state.reload(&config).is_err());
+        assert_eq!(state.notifications[0].name(), "mockup");
+        assert_eq!(state.reminder_interval, None);
+
+        config.providers.push(config::ProviderConfig {
+            name: "discord-db".to_string(),
+            settings: config::ProviderSettings::Discord(config::DiscordConfig {
+                webhook_url: secret::Secret::new(
+                    "https://discord.com/api/webhooks/1/a".to_string(),
+                ),
+                batch: false,
+                escalate_after: None,
+                quiet_hours: None,
+            }),
+        });
+        state.reload(&config).unwrap();
+        assert_eq!(state.notifications.len(), 1);
+        assert_eq!(state.notifications[0].name(), "discord-db");
+        assert_eq!(state.reminder_interval, config.reminder_interval);
+        assert_eq!(state.systemd.last_state, Some(Vec::new()));
+        assert_eq!(state.filter.silences().list().len(), 1);
+    }
+
+    #[test]
+    fn settings_of_the_start_require_a_restart() {
+        let config = test_config();
+        assert!(config.restart_required(&test_config()).is_empty());
+        let mut changed = test_config();
+        changed.reminder_interval = None;
+        changed.poll_interval = time::Duration::from_millis(500);
+        changed.outbox_max_age = time::Duration::from_secs(3600);
+        assert_eq!(
+            config.restart_required(&changed),
+            vec!["outbox-max-age", "poll-interval"]
+        );
+    }
+
+    #[test]
+    fn notify_keeps_failed_notification_in_outbox() {
+        let mut provider = MockupNotificationProvider::new();
+        provider.error = true;
+        let mut state = mockup_state();
+        state.notifications = Arc::new(vec![Box::new(provider)]);
+        state.enqueue(PendingNotification::Start).unwrap();
+        state.dispatch_outbox(true);
+        let outbox = state.outbox.lock().unwrap();
+        assert!(!outbox.has_due(state::unix_now()));
+        assert!(outbox.has_due(u64::MAX));
     }
 }
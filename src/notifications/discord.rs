@@ -4,90 +4,267 @@ SPDX-FileCopyrightText: 2021 localthomas
 SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
-use anyhow::{Context, Result};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
 use serde_json::json;
 use url::Url;
 
-use crate::status::{ActiveState, UnitStatus};
+use crate::{config::DiscordConfig, quiet_hours::QuietHours, state::unix_now, status::ActiveState};
+
+use super::{HttpResponse, NotificationKind, NotificationProvider, Severity, UnitNotification};
+use crate::outbox::PendingNotification;
+
+/// The maximum number of attempts to send a single message while being rate limited.
+const MAX_RATE_LIMITED_ATTEMPTS: u32 = 5;
+/// Rate limits that last longer than this are not waited for, but returned as error,
+/// so that the notification is retried later on.
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+/// The maximum number of embeds in a single Discord message.
+const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+/// The maximum number of characters of all embeds in a single Discord message.
+const MAX_EMBED_CHARACTERS_PER_MESSAGE: usize = 6000;
 
-use super::NotificationProvider;
+const COLOR_SUCCESS: u32 = 6610199;
+const COLOR_WARNING: u32 = 16098851;
+const COLOR_FAILURE: u32 = 13631488;
 
 #[derive(Clone)]
 pub struct Discord {
+    name: String,
     webhook_url: Url,
+    /// If set, all unit status of one notification are combined into as few messages as possible.
+    batch: bool,
+    escalate_after: Option<Duration>,
+    quiet_hours: Option<QuietHours>,
+    /// Shared between all clones, so that concurrent notifications respect the same bucket.
+    rate_limit: Arc<Mutex<RateLimit>>,
 }
 
 impl Discord {
-    /// Creates a new Discord notification provider with the given webhook URL as string.
-    /// The string must be a in a valid format for an URL.
-    pub fn new(webhook_url: &str) -> Result<Self> {
-        let url = Url::parse(webhook_url).context(format!(
-            "could not parse discord webhook url '{}'",
-            webhook_url
-        ))?;
-        Ok(Self { webhook_url: url })
-    }
-
-    /// Sends the status of one unit to the specified Discord webhook URL.
-    fn send_status(&self, status: &UnitStatus) -> Result<()> {
-        let (text, color) = if status.active_state() == &ActiveState::Active {
-            (format!("✔ {} recovered!", status.name()), 6610199)
-        } else {
-            (format!("❌ {} has failed!", status.name()), 13631488)
-        };
+    /// Creates a new Discord notification provider with the given name and configuration.
+    /// The webhook URL must be a in a valid format for an URL.
+    pub fn new(name: &str, config: &DiscordConfig) -> Result<Self> {
+        // the URL contains the token of the webhook and must not be part of the error
+        let url = Url::parse(config.webhook_url.expose())
+            .context("could not parse discord webhook url")?;
+        Ok(Self {
+            name: name.to_string(),
+            webhook_url: url,
+            batch: config.batch,
+            escalate_after: config.escalate_after,
+            quiet_hours: config.quiet_hours.clone(),
+            rate_limit: Arc::new(Mutex::new(RateLimit::default())),
+        })
+    }
+
+    /// Sends the notification about one unit to the specified Discord webhook URL.
+    fn send_notification(&self, notification: &UnitNotification) -> Result<()> {
+        let embed = unit_embed(notification);
         let payload = DiscordMessage {
-            content: text.clone(),
-            title: text.clone(),
-            description: "The following unit has entered a new state:".to_string(),
-            color: color,
-            fields: vec![
-                DiscordMessageField {
-                    name: "Name".to_string(),
-                    value: status.name().to_string(),
-                },
-                DiscordMessageField {
-                    name: "Description".to_string(),
-                    value: status.description().to_string(),
-                },
-                DiscordMessageField {
-                    name: "Load State".to_string(),
-                    value: format!("{}", status.load_state()),
-                },
-                DiscordMessageField {
-                    name: "Active State".to_string(),
-                    value: format!("{}", status.active_state()),
-                },
-                DiscordMessageField {
-                    name: "Sub State".to_string(),
-                    value: status.sub_state().to_string(),
-                },
-            ],
+            content: embed.title.clone(),
+            embeds: vec![embed],
         };
         self.send(payload)
     }
 
+    /// Sends all given notifications as digest with one embed per unit.
+    /// The embeds are split across multiple messages, if they exceed Discord's limits for a single message.
+    fn send_digest(&self, notifications: &[UnitNotification]) -> Result<()> {
+        let summary = digest_summary(notifications);
+        let embeds = notifications.iter().map(unit_embed).collect();
+        let messages = split_embeds(embeds, EmbedMetadata::now().length());
+        let message_count = messages.len();
+        for (index, embeds) in messages.into_iter().enumerate() {
+            let content = if message_count > 1 {
+                format!("{} ({}/{})", summary, index + 1, message_count)
+            } else {
+                summary.clone()
+            };
+            self.send(DiscordMessage { content, embeds })?;
+        }
+        Ok(())
+    }
+
     /// Sends the given webhook message for Discord to the configured webhook URL.
+    /// Waits before sending if the rate limit bucket of the webhook is exhausted and retries the
+    /// message if Discord answers with HTTP 429 (Too Many Requests).
     fn send(&self, payload: DiscordMessage) -> Result<()> {
-        super::http_post(&self.webhook_url, vec![("wait", "true")], payload.to_json())
-            .context("could not execute discord webhook")?;
-        Ok(())
+        let payload = payload.to_json();
+        for _ in 0..MAX_RATE_LIMITED_ATTEMPTS {
+            let delay = self
+                .rate_limit
+                .lock()
+                .expect("rate limit mutex is poisoned")
+                .delay(Instant::now());
+            wait_for_rate_limit(delay)?;
+
+            let response =
+                super::http_post(&self.webhook_url, vec![("wait", "true")], payload.clone())
+                    .context("could not execute discord webhook")?;
+            let mut rate_limit = self
+                .rate_limit
+                .lock()
+                .expect("rate limit mutex is poisoned");
+            rate_limit.update(&response, Instant::now());
+            if response.status == 429 {
+                let retry_after = retry_after(&response).unwrap_or(Duration::from_secs(1));
+                rate_limit.block_for(retry_after, Instant::now());
+                continue;
+            }
+            if !response.is_success() {
+                return Err(anyhow!(
+                    "discord webhook returned not-ok status code {}: {}",
+                    response.status,
+                    response.body
+                ));
+            }
+            return Ok(());
+        }
+        Err(anyhow!(
+            "discord webhook is still rate limited after {} attempts",
+            MAX_RATE_LIMITED_ATTEMPTS
+        ))
     }
 }
 
+/// Sleeps for the given delay of a rate limit or returns an error, if the delay is too long to wait for.
+fn wait_for_rate_limit(delay: Duration) -> Result<()> {
+    if delay > MAX_RATE_LIMIT_DELAY {
+        return Err(anyhow!(
+            "discord webhook is rate limited for {} seconds",
+            delay.as_secs()
+        ));
+    }
+    thread::sleep(delay);
+    Ok(())
+}
+
+/// The state of the rate limit bucket of a webhook, as reported by Discord.
+/// See <https://discord.com/developers/docs/topics/rate-limits>.
+#[derive(Default)]
+struct RateLimit {
+    /// Set if the bucket is exhausted and no request should be sent before this instant.
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Updates the bucket from the `X-RateLimit-*` headers of a response.
+    fn update(&mut self, response: &HttpResponse, now: Instant) {
+        let remaining = response
+            .header("x-ratelimit-remaining")
+            .and_then(|value| value.parse::<u32>().ok());
+        let reset_after = response
+            .header("x-ratelimit-reset-after")
+            .and_then(parse_seconds);
+        self.blocked_until = match (remaining, reset_after) {
+            (Some(0), Some(reset_after)) => Some(now + reset_after),
+            _ => None,
+        };
+    }
+
+    /// Blocks the bucket for the given duration, e.g. after receiving HTTP 429.
+    fn block_for(&mut self, duration: Duration, now: Instant) {
+        self.blocked_until = Some(now + duration);
+    }
+
+    /// Returns the time to wait before the next request can be sent.
+    fn delay(&self, now: Instant) -> Duration {
+        self.blocked_until
+            .map(|blocked_until| blocked_until.saturating_duration_since(now))
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the delay requested by a rate limited response,
+/// either from the `Retry-After` header or the `retry_after` field of the JSON body.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    response
+        .header("retry-after")
+        .and_then(parse_seconds)
+        .or_else(|| {
+            let body: serde_json::Value = serde_json::from_str(&response.body).ok()?;
+            parse_seconds(&body.get("retry_after")?.to_string())
+        })
+}
+
+/// Parses a (possibly fractional) number of seconds as sent by Discord.
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
 impl NotificationProvider for Discord {
-    fn execute(&self, states: Vec<UnitStatus>) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn escalate_after(&self) -> Option<Duration> {
+        self.escalate_after
+    }
+
+    fn quiet_hours(&self) -> Option<&QuietHours> {
+        self.quiet_hours.as_ref()
+    }
+
+    fn execute(
+        &self,
+        notifications: Vec<UnitNotification>,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
         // to make the closure being able to be send to another thread,
         // the Discord config needs to be cloned, so that it can be transferred to the thread
         let new_self: Discord = (*self).clone();
 
         Box::new(move || {
-            for status in &states {
-                new_self.send_status(status)?;
+            if new_self.batch && notifications.len() > 1 {
+                return new_self.send_digest(&notifications);
+            }
+            for notification in &notifications {
+                new_self.send_notification(notification)?;
             }
             Ok(())
         })
     }
 
+    fn execute_digest(
+        &self,
+        notifications: Vec<UnitNotification>,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        let new_self: Discord = (*self).clone();
+
+        Box::new(move || match notifications.as_slice() {
+            [notification] => new_self.send_notification(notification),
+            notifications => new_self.send_digest(notifications),
+        })
+    }
+
+    fn split_messages(&self, notification: PendingNotification) -> Vec<PendingNotification> {
+        match notification {
+            PendingNotification::Units(notifications) if self.batch => {
+                message_groups(notifications)
+                    .into_iter()
+                    .map(PendingNotification::Units)
+                    .collect()
+            }
+            PendingNotification::Units(notifications) => notifications
+                .into_iter()
+                .map(|notification| PendingNotification::Units(vec![notification]))
+                .collect(),
+            PendingNotification::Digest(notifications) => message_groups(notifications)
+                .into_iter()
+                .map(PendingNotification::Digest)
+                .collect(),
+            notification => vec![notification],
+        }
+    }
+
     fn execute_error(
         &self,
         error: &anyhow::Error,
@@ -103,10 +280,12 @@ impl NotificationProvider for Discord {
         Box::new(move || {
             let payload = DiscordMessage {
                 content: format!("{} internal error!", env!("CARGO_PKG_NAME")),
-                title: "Internal Error!".to_string(),
-                description: description.clone(),
-                color: 13631488,
-                fields: vec![],
+                embeds: vec![DiscordEmbed {
+                    title: "Internal Error!".to_string(),
+                    description: description.clone(),
+                    color: COLOR_FAILURE,
+                    fields: vec![],
+                }],
             };
             new_self.send(payload)
         })
@@ -123,18 +302,285 @@ impl NotificationProvider for Discord {
                     "{} is starting to listen to systemd...",
                     env!("CARGO_PKG_NAME")
                 ),
-                title: "Starting".to_string(),
-                description: "Successfully started without errors and now listening for changes on systemd units".to_string(),
-                color: 6610199,
-                fields: vec![],
+                embeds: vec![DiscordEmbed {
+                    title: "Starting".to_string(),
+                    description: "Successfully started without errors and now listening for changes on systemd units".to_string(),
+                    color: COLOR_SUCCESS,
+                    fields: vec![],
+                }],
+            };
+            new_self.send(payload)
+        })
+    }
+
+    fn execute_reconnected(
+        &self,
+        lost_since: u64,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        // to make the closure being able to be send to another thread,
+        // the Discord config needs to be cloned, so that it can be transferred to the thread
+        let new_self: Discord = (*self).clone();
+
+        Box::new(move || {
+            let payload = DiscordMessage {
+                content: format!(
+                    "{} is listening to systemd again",
+                    env!("CARGO_PKG_NAME")
+                ),
+                embeds: vec![DiscordEmbed {
+                    title: "Reconnected".to_string(),
+                    description: format!(
+                        "The connection to systemd was lost at {} and is restored now. Changes of units in the meantime are notified now.",
+                        crate::control::format_timestamp(lost_since)
+                    ),
+                    color: COLOR_SUCCESS,
+                    fields: vec![],
+                }],
             };
             new_self.send(payload)
         })
     }
+
+    fn check_connectivity(&self) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        let webhook_url = self.webhook_url.clone();
+        // a GET on the webhook URL returns the webhook without sending a message
+        Box::new(move || {
+            let response =
+                super::http_get(&webhook_url).context("could not reach discord webhook")?;
+            if !response.is_success() {
+                return Err(anyhow!(
+                    "discord webhook returned not-ok status code {}: {}",
+                    response.status,
+                    response.body
+                ));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Creates a short summary of all notifications in a digest, e.g. "❌ 2 units failed!".
+fn digest_summary(notifications: &[UnitNotification]) -> String {
+    let (mut failed, mut recovered, mut flapping, mut stabilised) = (0, 0, 0, 0);
+    let (mut still_failed, mut escalated, mut suppressed, mut forgotten) = (0, 0, 0, 0);
+    for notification in notifications {
+        match notification.kind {
+            NotificationKind::Changed
+                if notification.status.active_state() == &ActiveState::Active =>
+            {
+                recovered += 1
+            }
+            NotificationKind::Changed => failed += 1,
+            NotificationKind::Flapping { .. } => flapping += 1,
+            NotificationKind::Stabilised => stabilised += 1,
+            NotificationKind::Reminder { .. } => still_failed += 1,
+            NotificationKind::Escalation { .. } => escalated += 1,
+            NotificationKind::SilenceEnded { .. } => suppressed += 1,
+            NotificationKind::Forgotten => forgotten += 1,
+        }
+    }
+    let parts: Vec<String> = [
+        (failed, "❌", "failed"),
+        (recovered, "✔", "recovered"),
+        (flapping, "⚠", "flapping"),
+        (stabilised, "✔", "stabilised"),
+        (still_failed, "⏰", "still failed"),
+        (escalated, "🚨", "escalated"),
+        (suppressed, "🔕", "changed during a silence"),
+        (forgotten, "✔", "no longer listed"),
+    ]
+    .iter()
+    .filter(|(count, _, _)| *count > 0)
+    .map(|(count, symbol, text)| format!("{} {} units {}", symbol, count, text))
+    .collect();
+    format!("{}!", parts.join(", "))
+}
+
+/// Creates the embed that describes the notification about a single unit.
+fn unit_embed(notification: &UnitNotification) -> DiscordEmbed {
+    let status = &notification.status;
+    let (title, description) = match &notification.kind {
+        NotificationKind::Changed if status.active_state() == &ActiveState::Active => (
+            format!("✔ {} recovered!", status.name()),
+            "The following unit has entered a new state:".to_string(),
+        ),
+        NotificationKind::Changed => (
+            format!("❌ {} has failed!", status.name()),
+            "The following unit has entered a new state:".to_string(),
+        ),
+        NotificationKind::Flapping {
+            transitions,
+            window_secs,
+        } => (
+            format!("⚠ {} is flapping!", status.name()),
+            format!(
+                "The following unit changed its state {} times within {} seconds. Further changes are suppressed until it is stable again:",
+                transitions, window_secs
+            ),
+        ),
+        NotificationKind::Stabilised if status.active_state() == &ActiveState::Failed => (
+            format!("❌ {} stopped flapping, but has failed!", status.name()),
+            "The following unit is stable again and remains in this state:".to_string(),
+        ),
+        NotificationKind::Stabilised => (
+            format!("✔ {} stopped flapping!", status.name()),
+            "The following unit is stable again and remains in this state:".to_string(),
+        ),
+        NotificationKind::Reminder { failed_since } => (
+            format!(
+                "⏰ {} has been failed for {}!",
+                status.name(),
+                format_duration(unix_now().saturating_sub(*failed_since))
+            ),
+            "The following unit is still in a failed state:".to_string(),
+        ),
+        NotificationKind::Escalation { failed_since } => (
+            format!(
+                "🚨 {} is still failed after {}!",
+                status.name(),
+                format_duration(unix_now().saturating_sub(*failed_since))
+            ),
+            "The following unit was not fixed in time and is escalated:".to_string(),
+        ),
+        NotificationKind::SilenceEnded { comment, changes } => (
+            format!(
+                "🔕 {} changed {} times during a silence",
+                status.name(),
+                changes
+            ),
+            format!(
+                "The silence '{}' has ended. The last suppressed state of the following unit was:",
+                comment
+            ),
+        ),
+        NotificationKind::Forgotten => (
+            format!("✔ {} is no longer listed!", status.name()),
+            "The following failed unit is no longer listed by systemd, e.g. after a reset. Its last known state was:"
+                .to_string(),
+        ),
+    };
+    let severity = notification.severity();
+    let mut fields = vec![
+        DiscordMessageField {
+            name: "Severity".to_string(),
+            value: severity.to_string(),
+        },
+        DiscordMessageField {
+            name: "Name".to_string(),
+            value: status.name().to_string(),
+        },
+        DiscordMessageField {
+            name: "Description".to_string(),
+            value: status.description().to_string(),
+        },
+        DiscordMessageField {
+            name: "Load State".to_string(),
+            value: format!("{}", status.load_state()),
+        },
+        DiscordMessageField {
+            name: "Active State".to_string(),
+            value: format!("{}", status.active_state()),
+        },
+        DiscordMessageField {
+            name: "Sub State".to_string(),
+            value: status.sub_state().to_string(),
+        },
+    ];
+    fields.extend(
+        notification
+            .details
+            .iter()
+            .map(|(name, value)| DiscordMessageField {
+                name: name.clone(),
+                value: value.clone(),
+            }),
+    );
+    DiscordEmbed {
+        title,
+        description,
+        color: severity_color(severity),
+        fields,
+    }
+}
+
+/// Returns the color of an embed for a notification with the given severity.
+fn severity_color(severity: Severity) -> u32 {
+    match severity {
+        Severity::Info => COLOR_SUCCESS,
+        Severity::Warning => COLOR_WARNING,
+        Severity::Critical => COLOR_FAILURE,
+    }
+}
+
+/// Formats a duration in seconds in a short human readable form with at most two units, e.g. "1d 7h".
+fn format_duration(seconds: u64) -> String {
+    let units = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(seconds, |remaining, (unit_seconds, suffix)| {
+            let value = *remaining / unit_seconds;
+            *remaining %= unit_seconds;
+            Some((value, suffix))
+        })
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, suffix)| format!("{}{}", value, suffix))
+        .collect();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// Splits the notifications into groups whose embeds can each be sent as a single digest message,
+/// see [`split_embeds`].
+fn message_groups(notifications: Vec<UnitNotification>) -> Vec<Vec<UnitNotification>> {
+    let embeds = notifications.iter().map(unit_embed).collect();
+    let sizes: Vec<usize> = split_embeds(embeds, EmbedMetadata::now().length())
+        .iter()
+        .map(Vec::len)
+        .collect();
+    let mut notifications = notifications.into_iter();
+    sizes
+        .into_iter()
+        .map(|size| notifications.by_ref().take(size).collect())
+        .collect()
+}
+
+/// Splits the embeds into groups that can each be sent as a single message without exceeding
+/// Discord's limits for the number of embeds and the number of characters in embeds.
+/// `metadata_length` is the number of characters that are added to each embed when it is sent,
+/// e.g. the author and footer.
+fn split_embeds(embeds: Vec<DiscordEmbed>, metadata_length: usize) -> Vec<Vec<DiscordEmbed>> {
+    let mut messages = Vec::new();
+    let mut current: Vec<DiscordEmbed> = Vec::new();
+    let mut current_length = 0;
+    for embed in embeds {
+        let length = embed.length() + metadata_length;
+        if !current.is_empty()
+            && (current.len() >= MAX_EMBEDS_PER_MESSAGE
+                || current_length + length > MAX_EMBED_CHARACTERS_PER_MESSAGE)
+        {
+            messages.push(std::mem::take(&mut current));
+            current_length = 0;
+        }
+        current_length += length;
+        current.push(embed);
+    }
+    if !current.is_empty() {
+        messages.push(current);
+    }
+    messages
 }
 
 struct DiscordMessage {
     content: String,
+    embeds: Vec<DiscordEmbed>,
+}
+
+struct DiscordEmbed {
     title: String,
     description: String,
     color: u32,
@@ -146,12 +592,54 @@ struct DiscordMessageField {
     value: String,
 }
 
-impl DiscordMessage {
-    fn to_json(&self) -> serde_json::Value {
+/// The parts of an embed that are the same for all embeds of a message.
+struct EmbedMetadata {
+    author: String,
+    timestamp: String,
+    footer: String,
+}
+
+impl EmbedMetadata {
+    fn now() -> Self {
         let now = time::OffsetDateTime::now_utc();
         let hostname = gethostname::gethostname()
             .into_string()
             .unwrap_or_else(|_| "".to_string());
+        let time_format =
+            time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory]:[offset_minute]")
+                .expect("could not create time format string");
+        Self {
+            author: format!("{} on {}", env!("CARGO_PKG_NAME"), hostname),
+            timestamp: now
+                .format(&time::format_description::well_known::Rfc3339)
+                .expect("could not format timestamp as RFC3339"),
+            footer: format!(
+                "message created at system time: {}",
+                now.format(&time_format)
+                    .expect("could not format timestamp")
+            ),
+        }
+    }
+
+    /// Returns the number of characters that count towards the embed limits of Discord.
+    fn length(&self) -> usize {
+        self.author.chars().count() + self.footer.chars().count()
+    }
+}
+
+impl DiscordEmbed {
+    /// Returns the number of characters that count towards the embed limits of Discord.
+    fn length(&self) -> usize {
+        self.title.chars().count()
+            + self.description.chars().count()
+            + self
+                .fields
+                .iter()
+                .map(|field| field.name.chars().count() + field.value.chars().count())
+                .sum::<usize>()
+    }
+
+    fn to_json(&self, metadata: &EmbedMetadata) -> serde_json::Value {
         let fields: Vec<serde_json::Value> = self
             .fields
             .iter()
@@ -163,27 +651,168 @@ impl DiscordMessage {
                 })
             })
             .collect();
-        let time_format =
-            time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory]:[offset_minute]")
-                .expect("could not create time format string");
+
+        json!({
+            "author": {
+                "name": metadata.author,
+            },
+            "title": self.title,
+            "description": self.description,
+            "timestamp": metadata.timestamp,
+            "color": self.color,
+            "fields": fields,
+            "footer": {
+                "text": metadata.footer,
+            },
+        })
+    }
+}
+
+impl DiscordMessage {
+    fn to_json(&self) -> serde_json::Value {
+        let metadata = EmbedMetadata::now();
+        let embeds: Vec<serde_json::Value> = self
+            .embeds
+            .iter()
+            .map(|embed| embed.to_json(&metadata))
+            .collect();
 
         json!({
             "content": self.content,
-            "embeds": [
-                {
-                    "author": {
-                        "name": format!("{} on {}", env!("CARGO_PKG_NAME"), hostname),
-                    },
-                    "title": self.title,
-                    "description": self.description,
-                    "timestamp": now.format(&time::format_description::well_known::Rfc3339).expect("could not format timestamp as RFC3339"),
-                    "color": self.color,
-                    "fields": fields,
-                    "footer": {
-                        "text": format!("message created at system time: {}", now.format(&time_format).expect("could not format timestamp")),
-                    },
-                }
-            ],
+            "embeds": embeds,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn rate_limit_blocks_on_exhausted_bucket() {
+        let now = Instant::now();
+        let mut rate_limit = RateLimit::default();
+        rate_limit.update(
+            &response(
+                200,
+                &[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset-after", "1.5"),
+                ],
+                "",
+            ),
+            now,
+        );
+        assert_eq!(rate_limit.delay(now), Duration::from_millis(1500));
+
+        rate_limit.update(
+            &response(
+                200,
+                &[
+                    ("x-ratelimit-remaining", "4"),
+                    ("x-ratelimit-reset-after", "1.5"),
+                ],
+                "",
+            ),
+            now,
+        );
+        assert_eq!(rate_limit.delay(now), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_after_from_header_or_body() {
+        assert_eq!(
+            retry_after(&response(429, &[("retry-after", "2")], "")),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(&response(
+                429,
+                &[],
+                r#"{"retry_after": 0.25, "global": false}"#
+            )),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(retry_after(&response(429, &[], "")), None);
+    }
+
+    #[test]
+    fn long_rate_limits_are_not_waited_for() {
+        assert!(wait_for_rate_limit(MAX_RATE_LIMIT_DELAY + Duration::from_secs(1)).is_err());
+        assert!(wait_for_rate_limit(Duration::ZERO).is_ok());
+    }
+
+    fn embed(description_length: usize) -> DiscordEmbed {
+        DiscordEmbed {
+            title: String::new(),
+            description: "x".repeat(description_length),
+            color: 0,
+            fields: vec![],
+        }
+    }
+
+    #[test]
+    fn split_embeds_by_count() {
+        let embeds = (0..25).map(|_| embed(10)).collect();
+        let messages: Vec<usize> = split_embeds(embeds, 100)
+            .iter()
+            .map(|message| message.len())
+            .collect();
+        assert_eq!(messages, vec![10, 10, 5]);
+    }
+
+    #[test]
+    fn split_embeds_by_characters() {
+        let embeds = (0..5).map(|_| embed(2000)).collect();
+        let messages: Vec<usize> = split_embeds(embeds, 100)
+            .iter()
+            .map(|message| message.len())
+            .collect();
+        assert_eq!(messages, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn message_groups_keep_order_of_notifications() {
+        let notifications: Vec<UnitNotification> = (0..12)
+            .map(|index| {
+                UnitNotification::new(
+                    crate::state::tests::unit_status(&format!("{}.service", index), "failed"),
+                    NotificationKind::Changed,
+                )
+            })
+            .collect();
+        let groups = message_groups(notifications.clone());
+        assert_eq!(
+            groups.iter().map(Vec::len).collect::<Vec<usize>>(),
+            vec![10, 2]
+        );
+        assert_eq!(groups.concat(), notifications);
+    }
+
+    #[test]
+    fn split_embeds_keeps_oversized_embed() {
+        let messages = split_embeds(vec![embed(7000)], 0);
+        assert_eq!(messages.len(), 1);
+        assert!(split_embeds(vec![], 0).is_empty());
+    }
+
+    #[test]
+    fn format_duration_uses_two_largest_units() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(7 * 3600 + 59), "7h");
+        assert_eq!(format_duration(86400 + 7 * 3600 + 120), "1d 7h");
+        assert_eq!(format_duration(3 * 60 + 5), "3m 5s");
+    }
+}
@@ -6,21 +6,220 @@ SPDX-License-Identifier: MIT OR Apache-2.0
 
 use anyhow::{anyhow, Context, Result};
 use discord::Discord;
+use serde::{Deserialize, Serialize};
 
-use crate::{config::Config, status::UnitStatus};
+use crate::{
+    config::{Config, ProviderSettings},
+    outbox::PendingNotification,
+    quiet_hours::QuietHours,
+    status::{ActiveState, UnitStatus},
+};
 
 pub mod discord;
 
+/// A notification about a single unit that is handed to the notification providers.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UnitNotification {
+    pub status: UnitStatus,
+    pub kind: NotificationKind,
+    /// If set, the notification is only sent to the notification provider with this name.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// The severity assigned to the unit by the filter rules, if any.
+    #[serde(default)]
+    pub severity: Option<Severity>,
+    /// Additional details about the state of the unit as name and value pairs, e.g. its exit status.
+    #[serde(default)]
+    pub details: Vec<(String, String)>,
+}
+
+impl UnitNotification {
+    pub fn new(status: UnitStatus, kind: NotificationKind) -> Self {
+        Self {
+            status,
+            kind,
+            target: None,
+            severity: None,
+            details: Vec::new(),
+        }
+    }
+
+    /// Creates a notification that is only sent to the notification provider with the given name.
+    pub fn targeted(status: UnitStatus, kind: NotificationKind, provider: &str) -> Self {
+        Self {
+            status,
+            kind,
+            target: Some(provider.to_string()),
+            severity: None,
+            details: Vec::new(),
+        }
+    }
+
+    /// Assigns the given severity, which overrides the default severity of the notification.
+    pub fn with_severity(mut self, severity: Option<Severity>) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    /// Adds details about the state of the unit, which are shown by the notification providers.
+    pub fn with_details(mut self, details: Vec<(String, String)>) -> Self {
+        self.details = details;
+        self
+    }
+
+    /// Returns how urgently the user should be notified about this notification.
+    /// Without an assigned severity, it depends on the kind of the notification and the state of the unit.
+    pub fn severity(&self) -> Severity {
+        if let Some(severity) = self.severity {
+            return severity;
+        }
+        let failed = self.status.active_state() == &ActiveState::Failed;
+        match &self.kind {
+            NotificationKind::Changed if failed => Severity::Critical,
+            NotificationKind::Changed if self.status.active_state() == &ActiveState::Active => {
+                Severity::Info
+            }
+            NotificationKind::Changed => Severity::Warning,
+            NotificationKind::Flapping { .. } => Severity::Warning,
+            NotificationKind::Stabilised if failed => Severity::Critical,
+            NotificationKind::Stabilised => Severity::Info,
+            NotificationKind::Reminder { .. } => Severity::Warning,
+            NotificationKind::Escalation { .. } => Severity::Critical,
+            NotificationKind::SilenceEnded { .. } => Severity::Warning,
+            NotificationKind::Forgotten => Severity::Info,
+        }
+    }
+
+    /// Returns true, if this notification should be sent by the given notification provider.
+    /// Providers of an escalation tier only receive notifications that are explicitly targeted at them.
+    pub fn is_for(&self, provider: &dyn NotificationProvider) -> bool {
+        match &self.target {
+            Some(target) => target == provider.name(),
+            None => provider.escalate_after().is_none(),
+        }
+    }
+}
+
+/// The reason for a notification about a unit.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum NotificationKind {
+    /// The unit entered a new state, e.g. it failed or recovered.
+    Changed,
+    /// The unit changed its state `transitions` times within `window_secs` seconds.
+    /// Further changes are suppressed until it stabilised.
+    Flapping {
+        transitions: usize,
+        window_secs: u64,
+    },
+    /// The unit stopped flapping and remains in its current state.
+    Stabilised,
+    /// The unit is still failed since the given unix timestamp (in seconds).
+    Reminder { failed_since: u64 },
+    /// The unit is failed since the given unix timestamp (in seconds) and was not fixed within
+    /// the escalation delay of the notification provider.
+    Escalation { failed_since: u64 },
+    /// The silence with the given comment expired, during which `changes` changes of the unit were suppressed.
+    /// The status is the latest suppressed status of the unit.
+    SilenceEnded { comment: String, changes: usize },
+    /// The failed unit is no longer listed by systemd, e.g. after `systemctl reset-failed`.
+    /// The status is the last known status of the unit.
+    Forgotten,
+}
+
+/// How urgently the user should be notified, ordered from the lowest to the highest severity.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Severity::Info => "info",
+                Severity::Warning => "warning",
+                Severity::Critical => "critical",
+            }
+        )
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(anyhow!(
+                "unknown severity '{}', expected one of: info, warning, critical",
+                value
+            )),
+        }
+    }
+}
+
 /// Provides execution closures for notifications of multiple events.
 /// Should be thread safe, so that it can be invoked from multiple threads at the same time.
 /// Ideally, the implementations of this trait only hold the configuration necessary to construct
 /// the closures.
 pub trait NotificationProvider: Send + Sync {
+    /// A name that identifies this notification provider, e.g. for queued notifications.
+    fn name(&self) -> &str;
+
+    /// If set, this notification provider is part of an escalation tier: it is only notified about
+    /// units that are still failed after this delay and about their recovery.
+    fn escalate_after(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// If set, notifications below the bypass severity are held during the quiet hours
+    /// and delivered as digest once they end.
+    fn quiet_hours(&self) -> Option<&QuietHours> {
+        None
+    }
+
     // TODO: allow multiple Results?
     /// Execute produces a closure that when executed, notifies the user of the status of the given
     /// units.
     /// The closure that is created, can be executed in a different thread if desired.
-    fn execute(&self, states: Vec<UnitStatus>) -> Box<dyn FnOnce() -> Result<()> + 'static + Send>;
+    fn execute(
+        &self,
+        notifications: Vec<UnitNotification>,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send>;
+
+    /// Like [`NotificationProvider::execute`], but for notifications that were held back, e.g. during quiet hours.
+    /// Should summarize the units in as few messages as possible, if the notification system allows it.
+    fn execute_digest(
+        &self,
+        notifications: Vec<UnitNotification>,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        self.execute(notifications)
+    }
+
+    /// Splits the notification into the parts that are sent as separate messages, e.g. one message per unit.
+    /// The outbox delivers each part on its own, so that a failed delivery is retried without repeating
+    /// the messages that were already delivered.
+    /// The split must not change between attempts for the same notification.
+    fn split_messages(&self, notification: PendingNotification) -> Vec<PendingNotification> {
+        match notification {
+            PendingNotification::Units(notifications) => notifications
+                .into_iter()
+                .map(|notification| PendingNotification::Units(vec![notification]))
+                .collect(),
+            PendingNotification::Digest(notifications) => notifications
+                .into_iter()
+                .map(|notification| PendingNotification::Digest(vec![notification]))
+                .collect(),
+            notification => vec![notification],
+        }
+    }
 
     /// Produces a closure for notifying the user of an application error that ocurred in this program.
     /// Should be treated as alerts every time, if the notification system allows priority distinctions.
@@ -32,6 +231,20 @@ pub trait NotificationProvider: Send + Sync {
     /// Produces a closure for notifying when this program is started and ready.
     /// Should create a low priority informal message, if the notification system allows priority distinctions.
     fn execute_start(&self) -> Box<dyn FnOnce() -> Result<()> + 'static + Send>;
+
+    /// Produces a closure for notifying that the connection to systemd was restored after it was lost
+    /// at the given Unix timestamp (in seconds) and reported as application error.
+    fn execute_reconnected(
+        &self,
+        lost_since: u64,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send>;
+
+    /// Produces a closure that checks whether the notification system is reachable and accepts the
+    /// configuration, without notifying the user.
+    /// Notification providers that can not check this without sending a message succeed.
+    fn check_connectivity(&self) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        Box::new(|| Ok(()))
+    }
 }
 
 /// Creates a default set of notification providers with the given configuration.
@@ -39,10 +252,16 @@ pub trait NotificationProvider: Send + Sync {
 /// Any errors during the creation of any notification provider is returned and no list is created.
 pub fn create_notifications(config: &Config) -> Result<Vec<Box<dyn NotificationProvider>>> {
     let mut notifications: Vec<Box<dyn NotificationProvider>> = Vec::new();
-    if let Some(discord_webhook_url) = &config.discord_webhook_url {
-        let discord = Discord::new(discord_webhook_url)
-            .context("could not create discord notification provider")?;
-        notifications.push(Box::new(discord));
+    for provider in &config.providers {
+        match &provider.settings {
+            ProviderSettings::Discord(discord) => {
+                let discord = Discord::new(&provider.name, discord).context(format!(
+                    "could not create discord notification provider '{}'",
+                    provider.name
+                ))?;
+                notifications.push(Box::new(discord));
+            }
+        }
     }
     if notifications.is_empty() {
         return Err(anyhow!(
@@ -52,32 +271,204 @@ pub fn create_notifications(config: &Config) -> Result<Vec<Box<dyn NotificationP
     Ok(notifications)
 }
 
+/// The relevant parts of the response to an HTTP request.
+pub struct HttpResponse {
+    pub status: u16,
+    /// All headers of the response with lowercase names.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// Returns the value of the first header with the given name (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(header_name, _)| *header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns true, if the status code is in the 2xx range.
+    pub fn is_success(&self) -> bool {
+        (200..=299).contains(&self.status)
+    }
+}
+
+impl From<ureq::Response> for HttpResponse {
+    fn from(response: ureq::Response) -> Self {
+        let status = response.status();
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                response
+                    .header(&name)
+                    .map(|value| (name.to_lowercase(), value.to_string()))
+            })
+            .collect();
+        // the body is only used for diagnostics, so a body that can not be read is treated as empty
+        let body = response.into_string().unwrap_or_default();
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
 /// Executes a generic HTTP POST request to the given URL and with the query parameters applied.
 /// The payload is always JSON and the correct headers are automatically set for this type of payload.
+///
+/// Responses with a not-ok status code are not treated as errors, but returned to the caller,
+/// so that it can react on the status code and headers, e.g. for rate limits.
 fn http_post(
     url: &url::Url,
     query_params: Vec<(&str, &str)>,
     payload: serde_json::Value,
-) -> Result<()> {
+) -> Result<HttpResponse> {
+    let mut request = http_agent().request_url("POST", url);
+    for query_param in query_params {
+        request = request.query(query_param.0, query_param.1);
+    }
+    http_response(request.send_json(payload))
+}
+
+/// Executes a generic HTTP GET request to the given URL.
+/// Like for [`http_post`], responses with a not-ok status code are returned to the caller.
+fn http_get(url: &url::Url) -> Result<HttpResponse> {
+    http_response(http_agent().request_url("GET", url).call())
+}
+
+fn http_agent() -> ureq::Agent {
     let timeout_duration = std::time::Duration::from_secs(15);
-    let agent = ureq::AgentBuilder::new()
+    ureq::AgentBuilder::new()
         .timeout_read(timeout_duration)
         .timeout_write(timeout_duration)
-        .build();
+        .build()
+}
 
-    let mut request = agent.request_url("POST", url);
-    for query_param in query_params {
-        request = request.query(query_param.0, query_param.1);
+fn http_response(result: std::result::Result<ureq::Response, ureq::Error>) -> Result<HttpResponse> {
+    match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(HttpResponse::from(response)),
+        // the error is created without the URL, as it might contain secrets like the token of a webhook
+        Err(ureq::Error::Transport(transport)) => Err(anyhow!(
+            "could not execute HTTP request: {}: {}",
+            transport.kind(),
+            transport.message().unwrap_or_default()
+        )),
     }
+}
 
-    let response = request
-        .send_json(payload)
-        .context("could not execute POST on HTTP request")?;
-    if response.status() < 200 || response.status() > 299 {
-        return Err(anyhow!(
-            "HTTP POST request had not-ok status code: {}",
-            response.status()
-        ));
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Records all notifications instead of sending them and fails every execution if `error` is set.
+    pub struct MockupNotificationProvider {
+        pub error: bool,
+        /// If set, every execution of unit notifications fails after this number of them was sent.
+        pub fail_after: Option<usize>,
+        /// If set, the provider is part of an escalation tier.
+        pub escalate_after: Option<std::time::Duration>,
+        pub quiet_hours: Option<QuietHours>,
+        pub sent: Arc<Mutex<Vec<Vec<UnitNotification>>>>,
+    }
+
+    impl MockupNotificationProvider {
+        pub fn new() -> Self {
+            Self {
+                error: false,
+                fail_after: None,
+                escalate_after: None,
+                quiet_hours: None,
+                sent: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        fn result(&self) -> Result<()> {
+            if self.error {
+                Err(anyhow!("test"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl NotificationProvider for MockupNotificationProvider {
+        fn name(&self) -> &str {
+            "mockup"
+        }
+
+        fn execute(
+            &self,
+            notifications: Vec<UnitNotification>,
+        ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+            let result = self.result();
+            let sent = self.sent.clone();
+            let fail_after = self.fail_after;
+            Box::new(move || {
+                let mut sent = sent.lock().unwrap();
+                if fail_after.is_some_and(|limit| sent.len() >= limit) {
+                    return Err(anyhow!("test"));
+                }
+                sent.push(notifications);
+                result
+            })
+        }
+
+        fn execute_error(
+            &self,
+            _error: &anyhow::Error,
+        ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+            Box::new(|| Ok(()))
+        }
+
+        fn execute_start(&self) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+            let result = self.result();
+            Box::new(move || result)
+        }
+
+        fn execute_reconnected(
+            &self,
+            _lost_since: u64,
+        ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+            let result = self.result();
+            Box::new(move || result)
+        }
+
+        fn check_connectivity(&self) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+            let result = self.result();
+            Box::new(move || result)
+        }
+
+        fn escalate_after(&self) -> Option<std::time::Duration> {
+            self.escalate_after
+        }
+
+        fn quiet_hours(&self) -> Option<&QuietHours> {
+            self.quiet_hours.as_ref()
+        }
+    }
+
+    #[test]
+    fn assigned_severity_overrides_default_severity() {
+        let status = crate::state::tests::unit_status("test.service", "failed");
+        let notification = UnitNotification::new(status, NotificationKind::Changed);
+        assert_eq!(notification.severity(), Severity::Critical);
+        assert_eq!(
+            notification
+                .clone()
+                .with_severity(Some(Severity::Info))
+                .severity(),
+            Severity::Info
+        );
+        let reminder = UnitNotification {
+            kind: NotificationKind::Reminder { failed_since: 0 },
+            ..notification
+        };
+        assert_eq!(reminder.severity(), Severity::Warning);
     }
-    Ok(())
 }
@@ -0,0 +1,272 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::Config,
+    control::{self, ControlCommand},
+    dbus_systemd::{dbus::Connection, SystemdConnection},
+    filter::FilterState,
+    notifications::{self, NotificationKind, NotificationProvider, UnitNotification},
+    routing::Routes,
+    silences::Silences,
+    state::{unix_now, ChangedUnitStatus, SystemdStateImpl},
+    status::UnitStatus,
+};
+
+/// Notifies about the current state of a single unit, e.g. when executed by an `OnFailure=` unit.
+/// The change is determined against the state file and, once all notification providers were notified,
+/// the new state is recorded by the running daemon or in the state file directly, so that it is not notified twice.
+/// Changes that are suppressed by a silence or held during quiet hours are handed over the same way,
+/// so that the files of the daemon are only written by the daemon while it is running.
+pub fn run(config: &Config, unit: &str) -> Result<()> {
+    let conn = Connection::new(config.dbus_timeout).context("could not create connection")?;
+    let status = UnitStatus::from(
+        conn.unit_status(unit)
+            .context(format!("could not get the status of {}", unit))?,
+    );
+    let details = conn.unit_details(unit).unwrap_or_else(|error| {
+        eprintln!("Could not get the details of {}: {:#}", unit, error);
+        Vec::new()
+    });
+
+    let state_file_path = PathBuf::from(&config.state_file_path);
+    let change = ChangedUnitStatus {
+        old: SystemdStateImpl::new(state_file_path.clone())
+            .status(unit)
+            .cloned(),
+        new: status.clone(),
+    };
+    let notifications = notifications::create_notifications(config)
+        .context("could not create notifications provider")?;
+    let mut filter = crate::create_filter(
+        config,
+        Silences::new(Silences::file_path_for_state_file(&state_file_path)),
+    );
+    let notified = notify(
+        &mut filter,
+        &config.routes,
+        &notifications,
+        change,
+        details,
+        unix_now(),
+    )?;
+    let run = |command| {
+        control::run(
+            Path::new(&config.control_socket_path),
+            state_file_path.clone(),
+            config.outbox_max_age,
+            command,
+        )
+    };
+    for command in notified.commands {
+        run(command)?;
+    }
+    run(ControlCommand::Record { status })
+}
+
+/// The result of the notification about the change of a single unit.
+#[derive(Debug, Default, PartialEq)]
+struct Notified {
+    /// The names of the notified providers.
+    providers: Vec<String>,
+    /// The commands for the daemon that complete the notification, e.g. to hold it during quiet hours.
+    commands: Vec<ControlCommand>,
+}
+
+/// Notifies all selected notification providers synchronously about the change, unless the unit did not
+/// change since its last recorded state or the change is not of interest.
+/// A change that is suppressed by a silence and notifications for providers within their quiet hours
+/// (below their bypass severity) are returned as commands for the daemon instead.
+/// Returns an error, if any of the providers could not be notified.
+fn notify(
+    filter: &mut FilterState,
+    routes: &Routes,
+    notifications: &[Box<dyn NotificationProvider>],
+    change: ChangedUnitStatus,
+    details: Vec<(String, String)>,
+    now: u64,
+) -> Result<Notified> {
+    let name = change.new.name().clone();
+    let mut notified = Notified::default();
+    if change.old.as_ref() == Some(&change.new) {
+        println!("{} did not change since its last recorded state", name);
+        return Ok(notified);
+    }
+    if !filter.is_relevant(&change) {
+        println!("{} is ignored by the unit filter or the rules", name);
+        return Ok(notified);
+    }
+    if filter.silences().is_silenced(&name, now) {
+        println!("{} is suppressed by a silence", name);
+        notified
+            .commands
+            .push(ControlCommand::Suppress { status: change.new });
+        return Ok(notified);
+    }
+    let notification = UnitNotification::new(change.new.clone(), NotificationKind::Changed)
+        .with_severity(filter.severity(&change))
+        .with_details(details);
+
+    let mut errors = Vec::new();
+    for provider in notifications {
+        if !notification.is_for(provider.as_ref()) || !routes.allows(provider.name(), &notification)
+        {
+            continue;
+        }
+        let quiet_end = provider.quiet_hours().and_then(|quiet_hours| {
+            quiet_hours
+                .end(now)
+                .filter(|_| notification.severity() < quiet_hours.bypass_severity())
+        });
+        if let Some(until) = quiet_end {
+            println!(
+                "provider '{}': held during quiet hours until {}",
+                provider.name(),
+                control::format_timestamp(until)
+            );
+            notified.commands.push(ControlCommand::Hold {
+                provider: provider.name().to_string(),
+                notification: notification.clone(),
+                until,
+            });
+            continue;
+        }
+        match provider.execute(vec![notification.clone()])() {
+            Ok(()) => {
+                println!("provider '{}': notified about {}", provider.name(), name);
+                notified.providers.push(provider.name().to_string());
+            }
+            Err(error) => errors.push(format!("{}: {:#}", provider.name(), error)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "could not notify about {}: {}",
+            name,
+            errors.join("; ")
+        ));
+    }
+    Ok(notified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::UtcOffset;
+
+    use crate::{
+        notifications::{tests::MockupNotificationProvider, Severity},
+        quiet_hours::QuietHours,
+        state::tests::{temp_file_path, unit_status},
+    };
+
+    #[test]
+    fn only_changes_of_interest_are_notified() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![Box::new(provider)];
+        let notify = |old: Option<UnitStatus>, new: UnitStatus| {
+            notify(
+                &mut FilterState::new(),
+                &Routes::default(),
+                &notifications,
+                ChangedUnitStatus { old, new },
+                vec![("Result".to_string(), "exit-code".to_string())],
+                0,
+            )
+            .unwrap()
+            .providers
+        };
+
+        // the daemon already recorded the failure
+        assert!(notify(
+            Some(unit_status("test.service", "failed")),
+            unit_status("test.service", "failed")
+        )
+        .is_empty());
+        assert!(notify(None, unit_status("test.service", "active")).is_empty());
+        assert_eq!(
+            notify(
+                Some(unit_status("test.service", "active")),
+                unit_status("test.service", "failed")
+            ),
+            vec!["mockup"]
+        );
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0].details[0].1, "exit-code");
+    }
+
+    #[test]
+    fn failed_delivery_is_an_error() {
+        let mut provider = MockupNotificationProvider::new();
+        provider.error = true;
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![Box::new(provider)];
+        assert!(notify(
+            &mut FilterState::new(),
+            &Routes::default(),
+            &notifications,
+            ChangedUnitStatus {
+                old: None,
+                new: unit_status("test.service", "failed")
+            },
+            Vec::new(),
+            0,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn silenced_and_quiet_changes_are_handed_to_daemon() {
+        let path = temp_file_path();
+        let mut silences = Silences::new(path.clone());
+        silences
+            .add("*".to_string(), None, 0, 100, "".to_string())
+            .unwrap();
+        let mut provider = MockupNotificationProvider::new();
+        provider.quiet_hours = Some(
+            QuietHours::parse("00:00-12:00", Severity::Critical, Some(UtcOffset::UTC)).unwrap(),
+        );
+        let sent = provider.sent.clone();
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![Box::new(provider)];
+        let mut filter = FilterState::new().with_silences(silences);
+        let mut notify = |now: u64| {
+            notify(
+                &mut filter,
+                &Routes::default(),
+                &notifications,
+                ChangedUnitStatus {
+                    old: Some(unit_status("test.service", "failed")),
+                    new: unit_status("test.service", "active"),
+                },
+                Vec::new(),
+                now,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            notify(50).commands,
+            vec![ControlCommand::Suppress {
+                status: unit_status("test.service", "active")
+            }]
+        );
+        // only the daemon records the suppressed change
+        assert!(Silences::new(path).list()[0].suppressed.is_empty());
+
+        let notified = notify(3600);
+        assert!(notified.providers.is_empty());
+        assert!(matches!(
+            notified.commands.as_slice(),
+            [ControlCommand::Hold { provider, until: 43200, .. }] if provider == "mockup"
+        ));
+        assert!(sent.lock().unwrap().is_empty());
+    }
+}
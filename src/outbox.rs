@@ -0,0 +1,644 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifications::{NotificationProvider, UnitNotification},
+    routing::Routes,
+    state::unix_now,
+    status::ActiveState,
+};
+
+/// The delay before the first retry of a failed notification.
+const BACKOFF_BASE: Duration = Duration::from_secs(10);
+/// The maximum delay between two retries of a failed notification.
+const BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// The content of a notification that is waiting for delivery.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PendingNotification {
+    /// A notification about changed units, see [`crate::notifications::NotificationProvider::execute`].
+    Units(Vec<UnitNotification>),
+    /// Notifications about changed units that were held back and are delivered together,
+    /// see [`crate::notifications::NotificationProvider::execute_digest`].
+    Digest(Vec<UnitNotification>),
+    /// A notification about the start of the application, see [`crate::notifications::NotificationProvider::execute_start`].
+    Start,
+    /// A notification about the restored connection to systemd,
+    /// see [`crate::notifications::NotificationProvider::execute_reconnected`].
+    Reconnected {
+        /// Unix timestamp (in seconds) of the time the connection was lost.
+        lost_since: u64,
+    },
+}
+
+impl PendingNotification {
+    /// Returns the closure of the provider that delivers this notification.
+    pub fn execute(
+        self,
+        provider: &dyn NotificationProvider,
+    ) -> Box<dyn FnOnce() -> Result<()> + 'static + Send> {
+        match self {
+            PendingNotification::Units(notifications) => provider.execute(notifications),
+            PendingNotification::Digest(notifications) => provider.execute_digest(notifications),
+            PendingNotification::Start => provider.execute_start(),
+            PendingNotification::Reconnected { lost_since } => {
+                provider.execute_reconnected(lost_since)
+            }
+        }
+    }
+
+    /// Returns the part of this notification that should be sent by the given notification provider, if any.
+    /// Notifications about units are only sent to the provider if the routes allow it.
+    pub fn for_provider(
+        &self,
+        provider: &dyn NotificationProvider,
+        routes: &Routes,
+    ) -> Option<Self> {
+        let selected = |notifications: &[UnitNotification]| -> Vec<UnitNotification> {
+            notifications
+                .iter()
+                .filter(|notification| {
+                    notification.is_for(provider) && routes.allows(provider.name(), notification)
+                })
+                .cloned()
+                .collect()
+        };
+        match self {
+            PendingNotification::Units(notifications) => {
+                let selected = selected(notifications);
+                (!selected.is_empty()).then_some(PendingNotification::Units(selected))
+            }
+            PendingNotification::Digest(notifications) => {
+                let selected = selected(notifications);
+                (!selected.is_empty()).then_some(PendingNotification::Digest(selected))
+            }
+            // escalation tiers are only used for failures
+            PendingNotification::Start => provider
+                .escalate_after()
+                .is_none()
+                .then_some(PendingNotification::Start),
+            // the loss of the connection is reported to all providers, so is its restoration
+            PendingNotification::Reconnected { lost_since } => {
+                Some(PendingNotification::Reconnected {
+                    lost_since: *lost_since,
+                })
+            }
+        }
+    }
+}
+
+/// A single notification for a single notification provider that was not yet delivered successfully.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: u64,
+    /// The name of the notification provider that should deliver this notification.
+    pub provider: String,
+    pub notification: PendingNotification,
+    /// Unix timestamp (in seconds) of the time this entry was queued.
+    pub created_at: u64,
+    pub attempts: u32,
+    /// Unix timestamp (in seconds) of the earliest time for the next delivery attempt.
+    pub next_attempt_at: u64,
+    /// The error message of the last failed delivery attempt, if any.
+    pub last_error: Option<String>,
+    /// Unix timestamp (in seconds) until which this entry is held back on purpose, e.g. during quiet hours.
+    /// A held entry does not block later entries of the same provider and its age counts from this time.
+    #[serde(default)]
+    pub held_until: Option<u64>,
+    /// The number of messages of this notification that were already delivered,
+    /// see [`NotificationProvider::split_messages`]. They are skipped when the entry is retried.
+    #[serde(default)]
+    pub delivered_parts: usize,
+}
+
+/// The final outcome of a notification about a failed unit for a single notification provider,
+/// i.e. after its outbox entry was completed or dropped.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryOutcome {
+    pub provider: String,
+    /// Unix timestamp (in seconds) of the time the entry was completed or dropped.
+    pub at: u64,
+    /// The error that caused the entry to be dropped, or `None` if it was delivered.
+    pub error: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct OutboxFile {
+    next_id: u64,
+    entries: Vec<OutboxEntry>,
+    /// The latest outcome per notification provider for each unit, by the name of the unit.
+    #[serde(default)]
+    outcomes: HashMap<String, Vec<DeliveryOutcome>>,
+}
+
+/// A durable queue of notifications that are retried with exponential backoff until they are delivered
+/// or exceed the maximum age.
+/// Every change to the queue is written to disk immediately, so that pending notifications survive restarts.
+pub struct Outbox {
+    file_path: PathBuf,
+    max_age: Duration,
+    next_id: u64,
+    entries: Vec<OutboxEntry>,
+    outcomes: HashMap<String, Vec<DeliveryOutcome>>,
+}
+
+impl Outbox {
+    /// Creates a new outbox that is stored at the given path.
+    /// Any entries that were previously stored at this path are loaded.
+    pub fn new(file_path: PathBuf, max_age: Duration) -> Self {
+        let file: OutboxFile = fs::read(&file_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            file_path,
+            max_age,
+            next_id: file.next_id,
+            entries: file.entries,
+            outcomes: file.outcomes,
+        }
+    }
+
+    /// Returns the path of the outbox file that belongs to the given state file path,
+    /// i.e. a file next to the state file.
+    pub fn file_path_for_state_file(state_file_path: &Path) -> PathBuf {
+        state_file_path.with_extension("outbox.json")
+    }
+
+    /// Queues a new notification for the named notification provider that is due immediately.
+    pub fn push(&mut self, provider: &str, notification: PendingNotification) -> Result<()> {
+        let now = unix_now();
+        self.entries.push(OutboxEntry {
+            id: self.next_id,
+            provider: provider.to_string(),
+            notification,
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            held_until: None,
+            delivered_parts: 0,
+        });
+        self.next_id += 1;
+        self.save()
+    }
+
+    /// Holds the notifications for the named notification provider until the given unix timestamp (in seconds)
+    /// and delivers them as a single digest afterwards.
+    /// Notifications that are held until the same time are merged into the same digest.
+    pub fn hold(
+        &mut self,
+        provider: &str,
+        mut notifications: Vec<UnitNotification>,
+        until: u64,
+    ) -> Result<()> {
+        let existing = self.entries.iter_mut().find(|entry| {
+            entry.provider == provider && entry.held_until == Some(until) && entry.attempts == 0
+        });
+        if let Some(OutboxEntry {
+            notification: PendingNotification::Digest(held),
+            ..
+        }) = existing
+        {
+            held.append(&mut notifications);
+            return self.save();
+        }
+
+        self.entries.push(OutboxEntry {
+            id: self.next_id,
+            provider: provider.to_string(),
+            notification: PendingNotification::Digest(notifications),
+            created_at: unix_now(),
+            attempts: 0,
+            next_attempt_at: until,
+            last_error: None,
+            held_until: Some(until),
+            delivered_parts: 0,
+        });
+        self.next_id += 1;
+        self.save()
+    }
+
+    /// Returns true, if at least one entry is due for delivery at the given time.
+    pub fn has_due(&self, now: u64) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.next_attempt_at <= now)
+    }
+
+    /// Returns all entries that are due for delivery at the given time.
+    /// To keep the order of notifications for each provider, only the leading due entries of
+    /// each provider are returned, i.e. a provider's entries after one that is not yet due are held back.
+    /// Entries that are held on purpose are skipped instead.
+    pub fn due(&self, now: u64) -> Vec<OutboxEntry> {
+        let mut blocked_providers: Vec<&str> = Vec::new();
+        let mut due = Vec::new();
+        for entry in &self.entries {
+            if blocked_providers.contains(&entry.provider.as_str()) {
+                continue;
+            }
+            if entry.next_attempt_at <= now {
+                due.push(entry.clone());
+            } else if entry.held_until.is_none_or(|until| until <= now) {
+                blocked_providers.push(&entry.provider);
+            }
+        }
+        due
+    }
+
+    /// Returns all entries that contain a notification about the named unit.
+    pub fn entries_for_unit(&self, unit: &str) -> Vec<&OutboxEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| match &entry.notification {
+                PendingNotification::Units(notifications)
+                | PendingNotification::Digest(notifications) => notifications
+                    .iter()
+                    .any(|notification| notification.status.name() == unit),
+                PendingNotification::Start | PendingNotification::Reconnected { .. } => false,
+            })
+            .collect()
+    }
+
+    /// Returns the outcomes of the notifications about the named unit that were recorded at or after the given
+    /// unix timestamp (in seconds), i.e. for entries that are no longer in the outbox.
+    pub fn outcomes_for_unit(&self, unit: &str, since: u64) -> Vec<&DeliveryOutcome> {
+        self.outcomes
+            .get(unit)
+            .map(|outcomes| {
+                outcomes
+                    .iter()
+                    .filter(|outcome| outcome.at >= since)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes the entry with the given ID after it was delivered successfully.
+    pub fn complete(&mut self, id: u64) -> Result<()> {
+        self.remove(id, None)
+    }
+
+    /// Removes the entry with the given ID without delivering it, e.g. as its provider does not exist any more.
+    pub fn discard(&mut self, id: u64, error: String) -> Result<()> {
+        self.remove(id, Some(error))
+    }
+
+    /// Removes the entry with the given ID and records its outcome.
+    fn remove(&mut self, id: u64, error: Option<String>) -> Result<()> {
+        if let Some(index) = self.entries.iter().position(|entry| entry.id == id) {
+            let entry = self.entries.remove(index);
+            self.record_outcome(&entry, unix_now(), error);
+        }
+        self.save()
+    }
+
+    /// Records the outcome of the entry for all failed units it notifies about,
+    /// replacing any previous outcome for the same provider.
+    fn record_outcome(&mut self, entry: &OutboxEntry, now: u64, error: Option<String>) {
+        let notifications = match &entry.notification {
+            PendingNotification::Units(notifications)
+            | PendingNotification::Digest(notifications) => notifications,
+            PendingNotification::Start | PendingNotification::Reconnected { .. } => return,
+        };
+        for notification in notifications
+            .iter()
+            .filter(|notification| notification.status.active_state() == &ActiveState::Failed)
+        {
+            let outcomes = self
+                .outcomes
+                .entry(notification.status.name().clone())
+                .or_default();
+            outcomes.retain(|outcome| outcome.provider != entry.provider);
+            outcomes.push(DeliveryOutcome {
+                provider: entry.provider.clone(),
+                at: now,
+                error: error.clone(),
+            });
+        }
+    }
+
+    /// Records a failed delivery attempt for the entry with the given ID, after the given number of its messages
+    /// were delivered in total, and schedules the next attempt with exponential backoff and jitter.
+    pub fn reschedule(
+        &mut self,
+        id: u64,
+        now: u64,
+        error: String,
+        delivered_parts: usize,
+    ) -> Result<()> {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.attempts += 1;
+            entry.delivered_parts = delivered_parts;
+            entry.next_attempt_at = now + backoff(entry.attempts).as_secs();
+            entry.last_error = Some(error);
+        }
+        self.save()
+    }
+
+    /// Removes all entries that are older than the maximum age and returns them.
+    /// They are recorded as dropped with their last error.
+    pub fn expire(&mut self, now: u64) -> Result<Vec<OutboxEntry>> {
+        let max_age = self.max_age.as_secs();
+        let (expired, entries) = self.entries.drain(..).partition(|entry| {
+            now.saturating_sub(entry.held_until.unwrap_or(entry.created_at)) > max_age
+        });
+        self.entries = entries;
+        if !expired.is_empty() {
+            for entry in &expired {
+                let error = entry
+                    .last_error
+                    .clone()
+                    .unwrap_or_else(|| "exceeded the maximum age".to_string());
+                self.record_outcome(entry, now, Some(error));
+            }
+            self.save()?;
+        }
+        Ok(expired)
+    }
+
+    /// Writes the current entries to disk.
+    /// Outcomes that are older than the maximum age are dropped first, so that they do not pile up.
+    fn save(&mut self) -> Result<()> {
+        let max_age = self.max_age.as_secs();
+        let now = unix_now();
+        self.outcomes.retain(|_, outcomes| {
+            outcomes.retain(|outcome| now.saturating_sub(outcome.at) <= max_age);
+            !outcomes.is_empty()
+        });
+        let serialized = serde_json::to_string(&OutboxFile {
+            next_id: self.next_id,
+            entries: self.entries.clone(),
+            outcomes: self.outcomes.clone(),
+        })
+        .context("could not serialize outbox")?;
+        if let Some(dir_path) = self.file_path.parent() {
+            fs::create_dir_all(dir_path).context(format!(
+                "could not create directories for the outbox file ({:?})",
+                dir_path
+            ))?;
+        }
+        fs::write(&self.file_path, serialized).context(format!(
+            "could not write outbox to file ({:?})",
+            self.file_path
+        ))
+    }
+}
+
+/// Delivers the entries of a single notification provider in their order and stops at the first failure.
+/// Each entry is split into its messages and the messages that were delivered by previous attempts are skipped.
+/// Returns the ID of each attempted entry with the total number of its delivered messages and the result.
+pub fn deliver(
+    provider: &dyn NotificationProvider,
+    entries: Vec<OutboxEntry>,
+) -> Vec<(u64, usize, Result<()>)> {
+    let mut results = Vec::new();
+    for entry in entries {
+        let mut delivered_parts = entry.delivered_parts;
+        let mut result = Ok(());
+        for part in provider
+            .split_messages(entry.notification)
+            .into_iter()
+            .skip(entry.delivered_parts)
+        {
+            result = part.execute(provider)();
+            if result.is_err() {
+                break;
+            }
+            delivered_parts += 1;
+        }
+        let failed = result.is_err();
+        results.push((entry.id, delivered_parts, result));
+        if failed {
+            break;
+        }
+    }
+    results
+}
+
+/// Calculates the delay before the next delivery attempt after the given number of failed attempts.
+/// The delay doubles with each attempt up to a maximum and is randomized by ±20% to avoid thundering herds.
+fn backoff(attempts: u32) -> Duration {
+    let exponential = BACKOFF_BASE
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .unwrap_or(BACKOFF_MAX)
+        .min(BACKOFF_MAX);
+    exponential.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        notifications::{tests::MockupNotificationProvider, NotificationKind},
+        state::tests::{temp_file_path, unit_status},
+    };
+
+    #[test]
+    fn push_is_due_immediately() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        outbox.push("test", PendingNotification::Start).unwrap();
+        let due = outbox.due(unix_now());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].provider, "test");
+        assert_eq!(due[0].notification, PendingNotification::Start);
+    }
+
+    #[test]
+    fn entries_survive_reload() {
+        let path = temp_file_path();
+        let mut outbox = Outbox::new(path.clone(), Duration::from_secs(60));
+        outbox.push("test", PendingNotification::Start).unwrap();
+        let reloaded = Outbox::new(path, Duration::from_secs(60));
+        assert_eq!(reloaded.entries, outbox.entries);
+        assert_eq!(reloaded.next_id, 1);
+    }
+
+    #[test]
+    fn complete_removes_entry() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        outbox.push("test", PendingNotification::Start).unwrap();
+        outbox.complete(0).unwrap();
+        assert!(!outbox.has_due(unix_now()));
+    }
+
+    #[test]
+    fn outcomes_of_failed_units_are_recorded() {
+        let path = temp_file_path();
+        let mut outbox = Outbox::new(path.clone(), Duration::from_secs(60));
+        let notification = |name: &str, active_state: &str| {
+            UnitNotification::new(unit_status(name, active_state), NotificationKind::Changed)
+        };
+        outbox
+            .push(
+                "a",
+                PendingNotification::Units(vec![
+                    notification("failed.service", "failed"),
+                    notification("active.service", "active"),
+                ]),
+            )
+            .unwrap();
+        outbox
+            .push(
+                "b",
+                PendingNotification::Units(vec![notification("failed.service", "failed")]),
+            )
+            .unwrap();
+        outbox.complete(0).unwrap();
+        outbox.reschedule(1, 0, "error".to_string(), 0).unwrap();
+        let now = unix_now();
+        outbox.expire(now + 61).unwrap();
+
+        let outbox = Outbox::new(path, Duration::from_secs(60));
+        assert_eq!(
+            outbox.outcomes_for_unit("active.service", 0),
+            Vec::<&DeliveryOutcome>::new()
+        );
+        let outcomes = outbox.outcomes_for_unit("failed.service", 0);
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].provider, "a");
+        assert_eq!(outcomes[0].error, None);
+        assert_eq!(outcomes[1].provider, "b");
+        assert_eq!(outcomes[1].error, Some("error".to_string()));
+        // outcomes before the given time belong to an earlier failure
+        assert_eq!(
+            outbox.outcomes_for_unit("failed.service", now + 62).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn old_outcomes_are_dropped_when_saving() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        let notification = |name: &str| {
+            UnitNotification::new(unit_status(name, "failed"), NotificationKind::Changed)
+        };
+        outbox
+            .push(
+                "a",
+                PendingNotification::Units(vec![notification("old.service")]),
+            )
+            .unwrap();
+        outbox.complete(0).unwrap();
+        outbox.outcomes.get_mut("old.service").unwrap()[0].at -= 61;
+        outbox
+            .push(
+                "a",
+                PendingNotification::Units(vec![notification("new.service")]),
+            )
+            .unwrap();
+        outbox.complete(1).unwrap();
+
+        assert!(!outbox.outcomes.contains_key("old.service"));
+        assert_eq!(outbox.outcomes_for_unit("new.service", 0).len(), 1);
+    }
+
+    #[test]
+    fn reschedule_blocks_later_entries_of_same_provider() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        outbox.push("a", PendingNotification::Start).unwrap();
+        outbox.push("a", PendingNotification::Start).unwrap();
+        outbox.push("b", PendingNotification::Start).unwrap();
+        let now = unix_now();
+        outbox.reschedule(0, now, "error".to_string(), 0).unwrap();
+        let due = outbox.due(now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].provider, "b");
+        assert_eq!(outbox.entries[0].attempts, 1);
+        assert_eq!(outbox.entries[0].last_error, Some("error".to_string()));
+    }
+
+    #[test]
+    fn held_notifications_are_merged_and_do_not_block() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        let now = unix_now();
+        outbox.hold("a", vec![], now + 3600).unwrap();
+        outbox.hold("a", vec![], now + 3600).unwrap();
+        outbox.push("a", PendingNotification::Start).unwrap();
+        assert_eq!(outbox.entries.len(), 2);
+        let due = outbox.due(now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].notification, PendingNotification::Start);
+
+        // the age of held entries counts from their release
+        let expired = outbox.expire(now + 3600).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].notification, PendingNotification::Start);
+        let due = outbox.due(now + 3600);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].notification, PendingNotification::Digest(vec![]));
+    }
+
+    #[test]
+    fn retry_skips_delivered_messages() {
+        let mut provider = MockupNotificationProvider::new();
+        provider.fail_after = Some(1);
+        let sent = provider.sent.clone();
+        let notification = |name: &str| {
+            UnitNotification::new(unit_status(name, "failed"), NotificationKind::Changed)
+        };
+        let entry = OutboxEntry {
+            id: 0,
+            provider: "mockup".to_string(),
+            notification: PendingNotification::Units(vec![notification("a"), notification("b")]),
+            created_at: 0,
+            attempts: 0,
+            next_attempt_at: 0,
+            last_error: None,
+            held_until: None,
+            delivered_parts: 0,
+        };
+
+        let results = deliver(&provider, vec![entry.clone()]);
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].0, results[0].1), (0, 1));
+        assert!(results[0].2.is_err());
+
+        provider.fail_after = None;
+        let results = deliver(
+            &provider,
+            vec![OutboxEntry {
+                delivered_parts: 1,
+                ..entry
+            }],
+        );
+        assert_eq!(results[0].1, 2);
+        assert!(results[0].2.is_ok());
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![vec![notification("a")], vec![notification("b")]]
+        );
+    }
+
+    #[test]
+    fn expire_removes_old_entries() {
+        let mut outbox = Outbox::new(temp_file_path(), Duration::from_secs(60));
+        outbox.push("test", PendingNotification::Start).unwrap();
+        assert!(outbox.expire(unix_now()).unwrap().is_empty());
+        let expired = outbox.expire(unix_now() + 61).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(outbox.entries.is_empty());
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_maximum() {
+        assert!(backoff(1) >= BACKOFF_BASE.mul_f64(0.8));
+        assert!(backoff(1) <= BACKOFF_BASE.mul_f64(1.2));
+        assert!(backoff(3) >= (BACKOFF_BASE * 4).mul_f64(0.8));
+        assert!(backoff(100) <= BACKOFF_MAX.mul_f64(1.2));
+    }
+}
@@ -0,0 +1,100 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+/// A pattern for unit names, either a glob or, if prefixed with `re:`, a regular expression.
+/// Both kinds of patterns must match the whole name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Pattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        match pattern.strip_prefix("re:") {
+            Some(regex) => Ok(Pattern::Regex(
+                Regex::new(&format!("^(?:{})$", regex))
+                    .context(format!("could not parse regular expression '{}'", regex))?,
+            )),
+            None => Ok(Pattern::Glob(pattern.to_string())),
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob_matches(glob, text),
+            Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+impl std::convert::TryFrom<String> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(pattern: String) -> Result<Self> {
+        Pattern::parse(&pattern)
+    }
+}
+
+/// Returns true, if the text matches the glob pattern as a whole.
+/// The pattern supports `*` for any number of characters and `?` for exactly one character.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // iterative matching with backtracking to the last `*`
+    let (mut p, mut t) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = last_star {
+            // let the last `*` consume one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            last_star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literals_and_wildcards() {
+        assert!(glob_matches("test.service", "test.service"));
+        assert!(!glob_matches("test.service", "test.timer"));
+        assert!(glob_matches("*.service", "test.service"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("postgres*.service", "postgresql@14.service"));
+        assert!(!glob_matches("postgres*.service", "postgresql@14.timer"));
+        assert!(glob_matches("te?t.*", "test.mount"));
+        assert!(!glob_matches("te?t", "tet"));
+        assert!(glob_matches("*a*b", "xxaxxab"));
+    }
+
+    #[test]
+    fn regex_pattern_matches_whole_text() {
+        let pattern = Pattern::parse("re:systemd-.*-wait-online\\.service").unwrap();
+        assert!(pattern.matches("systemd-networkd-wait-online.service"));
+        assert!(!pattern.matches("systemd-networkd-wait-online.service.d"));
+        assert!(Pattern::parse("re:(").is_err());
+        assert!(Pattern::parse("*.timer").unwrap().matches("backup.timer"));
+    }
+}
@@ -0,0 +1,362 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Context, Result};
+use time::{OffsetDateTime, UtcOffset, Weekday};
+
+use crate::notifications::Severity;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+    Weekday::Thursday,
+    Weekday::Friday,
+    Weekday::Saturday,
+    Weekday::Sunday,
+];
+
+/// A schedule of times during which only notifications of at least the bypass severity are delivered
+/// immediately. All other notifications are held until the quiet hours end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    periods: Vec<QuietPeriod>,
+    bypass_severity: Severity,
+    /// The offset of the time in which the schedule is specified.
+    /// If not set, the offset of the local time zone at the evaluated time is used, which follows daylight saving time.
+    offset: Option<UtcOffset>,
+}
+
+/// A time range on the given weekdays. If the end is before the start, the range ends on the next day.
+#[derive(Debug, Clone, PartialEq)]
+struct QuietPeriod {
+    weekdays: Vec<Weekday>,
+    /// Minutes since midnight.
+    start: u32,
+    /// Minutes since midnight.
+    end: u32,
+}
+
+impl QuietPeriod {
+    fn contains(&self, weekday: Weekday, minute: u32) -> bool {
+        if self.start < self.end {
+            self.weekdays.contains(&weekday) && self.start <= minute && minute < self.end
+        } else {
+            (self.weekdays.contains(&weekday) && self.start <= minute)
+                || (self.weekdays.contains(&weekday.previous()) && minute < self.end)
+        }
+    }
+}
+
+impl QuietHours {
+    /// Parses a schedule like `Mon-Fri 22:00-07:00; Sat,Sun 00:00-24:00` with the times in the given offset
+    /// or, if no offset is given, in the local time zone.
+    /// The weekdays are optional and default to every day.
+    /// A period that ends before it starts, ends on the following day.
+    pub fn parse(
+        schedule: &str,
+        bypass_severity: Severity,
+        offset: Option<UtcOffset>,
+    ) -> Result<Self> {
+        let periods = schedule
+            .split(';')
+            .map(str::trim)
+            .filter(|period| !period.is_empty())
+            .map(|period| {
+                parse_period(period).context(format!("could not parse quiet period '{}'", period))
+            })
+            .collect::<Result<Vec<QuietPeriod>>>()?;
+        if periods.is_empty() {
+            return Err(anyhow!("quiet hours must contain at least one period"));
+        }
+        let quiet_hours = Self {
+            periods,
+            bypass_severity,
+            offset,
+        };
+        let always_quiet = WEEKDAYS.iter().all(|weekday| {
+            (0..MINUTES_PER_DAY).all(|minute| quiet_hours.is_quiet(*weekday, minute))
+        });
+        if always_quiet {
+            return Err(anyhow!("quiet hours must not cover the whole week"));
+        }
+        Ok(quiet_hours)
+    }
+
+    /// Notifications with at least this severity are delivered during quiet hours.
+    pub fn bypass_severity(&self) -> Severity {
+        self.bypass_severity
+    }
+
+    /// Returns the unix timestamp (in seconds) of the end of the current quiet hours,
+    /// or `None` if the given time is not within the quiet hours.
+    pub fn end(&self, now: u64) -> Option<u64> {
+        let (weekday, minute) = self.local_time(now);
+        if !self.is_quiet(weekday, minute) {
+            return None;
+        }
+        // the quiet hours end at the end of one of the periods, namely the first one after now that is not followed
+        // by another period; the schedule has a resolution of minutes and is never quiet for the whole week
+        let minute_of_week = weekday.number_days_from_monday() as u32 * MINUTES_PER_DAY + minute;
+        let minutes = self
+            .periods
+            .iter()
+            .flat_map(|period| {
+                period.weekdays.iter().map(move |weekday| {
+                    let day = weekday.number_days_from_monday() as u32
+                        + if period.start < period.end { 0 } else { 1 };
+                    day * MINUTES_PER_DAY + period.end
+                })
+            })
+            .map(|end| (end + MINUTES_PER_WEEK - minute_of_week) % MINUTES_PER_WEEK)
+            .filter(|minutes| *minutes > 0)
+            .filter(|minutes| {
+                let end = (minute_of_week + minutes) % MINUTES_PER_WEEK;
+                !self.is_quiet(
+                    WEEKDAYS[(end / MINUTES_PER_DAY) as usize],
+                    end % MINUTES_PER_DAY,
+                )
+            })
+            .min()?;
+        let end = now - now % 60 + minutes as u64 * 60;
+        if self.offset.is_some() {
+            return Some(end);
+        }
+
+        // the end is given in local time, so it is shifted, if the offset changes until then, e.g. by daylight saving time
+        let shift = self.offset_at(end).whole_seconds() - self.offset_at(now).whole_seconds();
+        let adjusted = (end as i64 - shift as i64) as u64;
+        if shift != 0 && adjusted > now && !self.is_quiet_at(adjusted) {
+            Some(adjusted)
+        } else {
+            Some(end)
+        }
+    }
+
+    fn is_quiet_at(&self, timestamp: u64) -> bool {
+        let (weekday, minute) = self.local_time(timestamp);
+        self.is_quiet(weekday, minute)
+    }
+
+    /// Returns the weekday and the minutes since midnight of the unix timestamp (in seconds) in the time of the schedule.
+    fn local_time(&self, timestamp: u64) -> (Weekday, u32) {
+        let date_time = OffsetDateTime::from_unix_timestamp(timestamp as i64)
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+            .to_offset(self.offset_at(timestamp));
+        (
+            date_time.weekday(),
+            date_time.hour() as u32 * 60 + date_time.minute() as u32,
+        )
+    }
+
+    /// Returns the offset of the time of the schedule at the unix timestamp (in seconds).
+    fn offset_at(&self, timestamp: u64) -> UtcOffset {
+        self.offset
+            .unwrap_or_else(|| local_offset_at(timestamp).unwrap_or(UtcOffset::UTC))
+    }
+
+    fn is_quiet(&self, weekday: Weekday, minute: u32) -> bool {
+        self.periods
+            .iter()
+            .any(|period| period.contains(weekday, minute))
+    }
+}
+
+/// Returns the offset of the local time zone at the given unix timestamp (in seconds), as determined by the C library
+/// from the `TZ` environment variable or `/etc/localtime`.
+/// Unlike [`UtcOffset::current_local_offset`], this also works while the program is multi-threaded,
+/// as the environment is never modified by this program.
+fn local_offset_at(timestamp: u64) -> Option<UtcOffset> {
+    let timestamp = libc::time_t::try_from(timestamp).ok()?;
+    // SAFETY: an all-zero `tm` is a valid value and `localtime_r` only writes to the given buffer
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::localtime_r(&timestamp, &mut tm) }.is_null() {
+        return None;
+    }
+    UtcOffset::from_whole_seconds(i32::try_from(tm.tm_gmtoff).ok()?).ok()
+}
+
+/// Parses a single period like `Mon-Fri 22:00-07:00` or `22:00-07:00`.
+fn parse_period(period: &str) -> Result<QuietPeriod> {
+    let (weekdays, times) = match period.rsplit_once(char::is_whitespace) {
+        Some((weekdays, times)) => (parse_weekdays(weekdays.trim())?, times),
+        None => (WEEKDAYS.to_vec(), period),
+    };
+    let (start, end) = times
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected a time range like '22:00-07:00'"))?;
+    Ok(QuietPeriod {
+        weekdays,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    })
+}
+
+/// Parses a list of weekdays and weekday ranges like `Mon-Wed,Fri`.
+fn parse_weekdays(weekdays: &str) -> Result<Vec<Weekday>> {
+    let mut result = Vec::new();
+    for part in weekdays.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let mut weekday = parse_weekday(first)?;
+                let last = parse_weekday(last)?;
+                result.push(weekday);
+                while weekday != last {
+                    weekday = weekday.next();
+                    result.push(weekday);
+                }
+            }
+            None => result.push(parse_weekday(part)?),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_weekday(weekday: &str) -> Result<Weekday> {
+    WEEKDAYS
+        .iter()
+        .find(|candidate| {
+            candidate.to_string()[..3].eq_ignore_ascii_case(weekday.trim())
+                || candidate.to_string().eq_ignore_ascii_case(weekday.trim())
+        })
+        .copied()
+        .ok_or_else(|| anyhow!("unknown weekday '{}'", weekday))
+}
+
+/// Parses a time like `07:30` as minutes since midnight. `24:00` is allowed as end of the day.
+fn parse_time(time: &str) -> Result<u32> {
+    let (hours, minutes) = time
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected a time like '07:30', got '{}'", time))?;
+    let hours: u32 = hours
+        .parse()
+        .context(format!("could not parse hours of '{}'", time))?;
+    let minutes: u32 = minutes
+        .parse()
+        .context(format!("could not parse minutes of '{}'", time))?;
+    let total = hours * 60 + minutes;
+    if minutes >= 60 || total > MINUTES_PER_DAY {
+        return Err(anyhow!("'{}' is not a valid time of day", time));
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday, 2024-01-01 00:00:00 UTC
+    const MONDAY: u64 = 1704067200;
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+
+    fn quiet_hours(schedule: &str) -> QuietHours {
+        QuietHours::parse(schedule, Severity::Critical, Some(UtcOffset::UTC)).unwrap()
+    }
+
+    #[test]
+    fn overnight_period_ends_next_day() {
+        let quiet_hours = quiet_hours("Mon-Fri 22:00-07:00");
+        assert_eq!(quiet_hours.end(MONDAY + 21 * HOUR), None);
+        assert_eq!(
+            quiet_hours.end(MONDAY + 23 * HOUR + 30),
+            Some(MONDAY + DAY + 7 * HOUR)
+        );
+        // monday morning belongs to the period starting on sunday
+        assert_eq!(quiet_hours.end(MONDAY + 6 * HOUR), None);
+        // friday night ends on saturday
+        assert_eq!(
+            quiet_hours.end(MONDAY + 4 * DAY + 23 * HOUR),
+            Some(MONDAY + 5 * DAY + 7 * HOUR)
+        );
+    }
+
+    #[test]
+    fn adjacent_periods_are_joined() {
+        let quiet_hours = quiet_hours("Sat,Sun 00:00-24:00; Fri 18:00-24:00");
+        assert_eq!(
+            quiet_hours.end(MONDAY + 4 * DAY + 20 * HOUR),
+            Some(MONDAY + 7 * DAY)
+        );
+    }
+
+    #[test]
+    fn end_is_first_minute_that_is_not_quiet() {
+        for schedule in [
+            "Mon-Fri 22:00-07:00; Sat,Sun 00:00-24:00",
+            "Sun 23:00-01:00; Mon 01:00-02:00",
+            "Tue-Thu 05:00-05:00; Sat 12:00-13:00",
+        ] {
+            let quiet_hours = quiet_hours(schedule);
+            for now in (MONDAY..MONDAY + 7 * DAY).step_by(17 * 60 + 7) {
+                let mut expected = None;
+                if quiet_hours.is_quiet_at(now) {
+                    let mut end = now - now % 60 + 60;
+                    while quiet_hours.is_quiet_at(end) {
+                        end += 60;
+                    }
+                    expected = Some(end);
+                }
+                assert_eq!(quiet_hours.end(now), expected, "{} at {}", schedule, now);
+            }
+        }
+    }
+
+    #[test]
+    fn period_without_weekdays_applies_every_day() {
+        let quiet_hours = quiet_hours("01:00-02:00");
+        for day in 0..7 {
+            assert_eq!(
+                quiet_hours.end(MONDAY + day * DAY + HOUR),
+                Some(MONDAY + day * DAY + 2 * HOUR)
+            );
+        }
+    }
+
+    #[test]
+    fn offset_is_applied() {
+        let quiet_hours = QuietHours::parse(
+            "00:00-01:00",
+            Severity::Critical,
+            Some(UtcOffset::from_hms(2, 0, 0).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(quiet_hours.end(MONDAY), None);
+        assert_eq!(quiet_hours.end(MONDAY - 2 * HOUR), Some(MONDAY - HOUR));
+    }
+
+    #[test]
+    fn local_offset_is_determined_while_multi_threaded() {
+        let offset = std::thread::spawn(|| local_offset_at(MONDAY))
+            .join()
+            .unwrap();
+        assert!(offset.is_some());
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for schedule in [
+            "",
+            "22:00",
+            "Foo 22:00-07:00",
+            "25:00-07:00",
+            "00:00-24:00",
+            "Mon-Sun 00:00-00:00",
+        ] {
+            assert!(
+                QuietHours::parse(schedule, Severity::Critical, Some(UtcOffset::UTC)).is_err(),
+                "{}",
+                schedule
+            );
+        }
+    }
+}
@@ -0,0 +1,164 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{fmt, time::Duration, time::Instant};
+
+/// The delay before the first attempt to reconnect, which doubles with every failed attempt.
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// The maximum delay between two attempts to reconnect.
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Marks errors of the connection to systemd, e.g. after a restart of the system bus or a timed out call.
+/// These errors are considered transient and are handled by reconnecting, see [`Reconnection`].
+#[derive(Debug)]
+pub struct ConnectionLost;
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lost the connection to systemd")
+    }
+}
+
+impl ConnectionLost {
+    /// Returns true, if the error or any of its causes is marked as [`ConnectionLost`].
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
+    }
+}
+
+/// The state of an outage of the connection after a failed attempt.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outage {
+    /// The connection was available before this attempt.
+    Started,
+    /// The connection is lost for a time within the budget.
+    Ongoing,
+    /// The connection is lost for longer than the budget.
+    Persistent,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LostConnection {
+    since: Instant,
+    /// Unix timestamp (in seconds) of the time the connection was lost.
+    since_unix: u64,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Tracks an outage of the connection to systemd and schedules the attempts to reconnect with exponential backoff.
+pub struct Reconnection {
+    /// The time for which the connection is retried, before the outage is considered persistent.
+    budget: Duration,
+    lost: Option<LostConnection>,
+}
+
+impl Reconnection {
+    pub fn new(budget: Duration) -> Self {
+        Self { budget, lost: None }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// Returns true, if the connection is currently lost.
+    pub fn is_lost(&self) -> bool {
+        self.lost.is_some()
+    }
+
+    /// Returns true, if the connection is available or the next attempt to reconnect is due.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.lost.is_none_or(|lost| now >= lost.next_attempt)
+    }
+
+    /// Records a failed attempt at the given time and schedules the next one.
+    pub fn failed(&mut self, now: Instant, unix_now: u64) -> Outage {
+        let (outage, mut lost) = match self.lost {
+            Some(lost) => (Outage::Ongoing, lost),
+            None => (
+                Outage::Started,
+                LostConnection {
+                    since: now,
+                    since_unix: unix_now,
+                    attempts: 0,
+                    next_attempt: now,
+                },
+            ),
+        };
+        lost.attempts += 1;
+        lost.next_attempt = now + backoff(lost.attempts);
+        self.lost = Some(lost);
+        if now.saturating_duration_since(lost.since) >= self.budget {
+            Outage::Persistent
+        } else {
+            outage
+        }
+    }
+
+    /// Records a successful attempt and returns the Unix timestamp (in seconds) of the time the connection was lost,
+    /// if it was lost before.
+    pub fn succeeded(&mut self) -> Option<u64> {
+        self.lost.take().map(|lost| lost.since_unix)
+    }
+}
+
+/// Returns the delay before the next attempt after the given number of failed attempts.
+fn backoff(attempts: u32) -> Duration {
+    BACKOFF_BASE
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .unwrap_or(BACKOFF_MAX)
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn reconnection_backs_off_until_budget_is_exceeded() {
+        let start = Instant::now();
+        let mut reconnection = Reconnection::new(Duration::from_secs(10));
+        assert!(reconnection.is_due(start));
+
+        assert_eq!(reconnection.failed(start, 100), Outage::Started);
+        assert!(reconnection.is_lost());
+        assert!(!reconnection.is_due(start));
+        assert!(reconnection.is_due(start + BACKOFF_BASE));
+        assert_eq!(
+            reconnection.failed(start + BACKOFF_BASE, 101),
+            Outage::Ongoing
+        );
+        assert!(!reconnection.is_due(start + BACKOFF_BASE * 2));
+        assert_eq!(
+            reconnection.failed(start + Duration::from_secs(10), 110),
+            Outage::Persistent
+        );
+
+        assert_eq!(reconnection.succeeded(), Some(100));
+        assert!(!reconnection.is_lost());
+        assert_eq!(reconnection.succeeded(), None);
+        // without a budget, the first failure is persistent
+        assert_eq!(
+            Reconnection::new(Duration::ZERO).failed(start, 100),
+            Outage::Persistent
+        );
+        assert_eq!(backoff(100), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn connection_lost_is_found_in_context() {
+        let error = Err::<(), _>(anyhow!("broken pipe"))
+            .context("could not list units")
+            .context(ConnectionLost)
+            .context("error during main loop")
+            .unwrap_err();
+        assert!(ConnectionLost::is_cause_of(&error));
+        assert!(!ConnectionLost::is_cause_of(&anyhow!("other")));
+    }
+}
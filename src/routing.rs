@@ -0,0 +1,140 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    notifications::{Severity, UnitNotification},
+    pattern::Pattern,
+};
+
+/// Sends the notifications about matching units to the named notification providers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Route {
+    pub providers: Vec<String>,
+    /// If set, only units matching this pattern are routed.
+    #[serde(default)]
+    pub units: Option<Pattern>,
+    /// If set, only notifications with at least this severity are routed.
+    #[serde(default)]
+    pub min_severity: Option<Severity>,
+}
+
+impl Route {
+    /// Parses a route like `discord-db postgresql*.service critical`, i.e. a comma separated list of
+    /// provider names, a unit pattern and an optional minimum severity.
+    pub fn parse(route: &str) -> Result<Self> {
+        let parts: Vec<&str> = route.split_whitespace().collect();
+        let (providers, units, min_severity) = match parts.as_slice() {
+            [providers, units] => (providers, units, None),
+            [providers, units, min_severity] => (providers, units, Some(min_severity.parse()?)),
+            _ => {
+                return Err(anyhow!(
+                    "expected providers, a unit pattern and an optional severity"
+                ))
+            }
+        };
+        Ok(Self {
+            providers: providers.split(',').map(str::to_string).collect(),
+            units: Some(Pattern::parse(units)?),
+            min_severity,
+        })
+    }
+
+    fn matches(&self, notification: &UnitNotification) -> bool {
+        self.units
+            .as_ref()
+            .is_none_or(|units| units.matches(notification.status.name()))
+            && self
+                .min_severity
+                .is_none_or(|min_severity| notification.severity() >= min_severity)
+    }
+}
+
+/// The routes of all notifications about units.
+/// Without any routes, all notifications are sent to all notification providers.
+#[derive(Debug, Clone, Default)]
+pub struct Routes(pub Vec<Route>);
+
+impl Routes {
+    /// Parses a list of routes separated by `;`, see [`Route::parse`].
+    pub fn parse(routes: &str) -> Result<Self> {
+        routes
+            .split(';')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(|route| Route::parse(route).context(format!("could not parse route '{}'", route)))
+            .collect::<Result<Vec<Route>>>()
+            .map(Self)
+    }
+
+    /// Returns an error, if a route refers to a notification provider that does not exist.
+    pub fn validate(&self, provider_names: &[&str]) -> Result<()> {
+        for provider in self.0.iter().flat_map(|route| &route.providers) {
+            if !provider_names.contains(&provider.as_str()) {
+                return Err(anyhow!(
+                    "route refers to unknown notification provider '{}'",
+                    provider
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true, if the notification should be sent to the named notification provider,
+    /// i.e. if there are no routes or at least one route to this provider matches.
+    pub fn allows(&self, provider: &str, notification: &UnitNotification) -> bool {
+        self.0.is_empty()
+            || self.0.iter().any(|route| {
+                route.providers.iter().any(|name| name == provider) && route.matches(notification)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{notifications::NotificationKind, state::tests::unit_status};
+
+    fn notification(name: &str, active_state: &str) -> UnitNotification {
+        UnitNotification::new(unit_status(name, active_state), NotificationKind::Changed)
+    }
+
+    #[test]
+    fn without_routes_everything_is_allowed() {
+        let routes = Routes::default();
+        assert!(routes.allows("any", &notification("test.service", "failed")));
+    }
+
+    #[test]
+    fn routes_select_providers_by_unit_and_severity() {
+        let routes =
+            Routes::parse("db postgresql*.service; platform *; pager,platform * critical").unwrap();
+        let database = notification("postgresql.service", "active");
+        let failed = notification("nginx.service", "failed");
+        let recovered = notification("nginx.service", "active");
+
+        assert!(routes.allows("db", &database));
+        assert!(!routes.allows("db", &failed));
+        assert!(routes.allows("platform", &database));
+        assert!(routes.allows("platform", &recovered));
+        assert!(routes.allows("pager", &failed));
+        assert!(!routes.allows("pager", &recovered));
+        assert!(!routes.allows("unknown", &failed));
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        assert!(Routes::parse("db").is_err());
+        assert!(Routes::parse("db * urgent").is_err());
+        assert!(Routes::parse("db * critical extra").is_err());
+        let routes = Routes::parse("db *").unwrap();
+        assert!(routes.validate(&["db"]).is_ok());
+        assert!(routes.validate(&["discord"]).is_err());
+    }
+}
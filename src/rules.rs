@@ -0,0 +1,334 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use serde::Deserialize;
+
+use crate::{
+    notifications::Severity, pattern::Pattern, state::ChangedUnitStatus, status::UnitStatus,
+};
+
+/// A rule that decides about the changes of units it matches.
+/// All set conditions must match; states are matched by their name like `failed` or `not-found`
+/// and states unknown to this program by `unknown: <name>`.
+/// Conditions on the old state never match the first status of a unit.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rule {
+    #[serde(default)]
+    pub unit: Option<Pattern>,
+    #[serde(default)]
+    pub old_load_state: Option<Pattern>,
+    #[serde(default)]
+    pub old_active_state: Option<Pattern>,
+    #[serde(default)]
+    pub old_sub_state: Option<Pattern>,
+    #[serde(default)]
+    pub new_load_state: Option<Pattern>,
+    #[serde(default)]
+    pub new_active_state: Option<Pattern>,
+    #[serde(default)]
+    pub new_sub_state: Option<Pattern>,
+    pub action: Action,
+}
+
+/// What happens with a change that matches a rule.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Notify about the change and stop the evaluation.
+    Notify,
+    /// Ignore the change and stop the evaluation.
+    Ignore,
+    /// Assign the severity to the change and continue with the next rule.
+    Severity(Severity),
+}
+
+/// The result of evaluating the rules for a change.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Decision {
+    pub notify: bool,
+    /// The severity assigned by the last matching severity rule, if any.
+    pub severity: Option<Severity>,
+}
+
+impl Rule {
+    /// Creates a rule with the given action that matches every change.
+    pub fn new(action: Action) -> Self {
+        Self {
+            unit: None,
+            old_load_state: None,
+            old_active_state: None,
+            old_sub_state: None,
+            new_load_state: None,
+            new_active_state: None,
+            new_sub_state: None,
+            action,
+        }
+    }
+
+    fn matches(&self, change: &ChangedUnitStatus) -> bool {
+        fn matches(pattern: &Option<Pattern>, value: Option<String>) -> bool {
+            match (pattern, value) {
+                (None, _) => true,
+                (Some(pattern), Some(value)) => pattern.matches(&value),
+                (Some(_), None) => false,
+            }
+        }
+        let old = change.old.as_ref();
+        let new = Some(&change.new);
+        let load_state = |status: Option<&UnitStatus>| status.map(|s| s.load_state().to_string());
+        let active_state =
+            |status: Option<&UnitStatus>| status.map(|s| s.active_state().to_string());
+        let sub_state = |status: Option<&UnitStatus>| status.map(|s| s.sub_state().clone());
+
+        matches(&self.unit, Some(change.new.name().clone()))
+            && matches(&self.old_load_state, load_state(old))
+            && matches(&self.old_active_state, active_state(old))
+            && matches(&self.old_sub_state, sub_state(old))
+            && matches(&self.new_load_state, load_state(new))
+            && matches(&self.new_active_state, active_state(new))
+            && matches(&self.new_sub_state, sub_state(new))
+    }
+}
+
+/// An ordered list of rules that is evaluated for every change of a unit.
+#[derive(Debug, Clone)]
+pub struct Rules(pub Vec<Rule>);
+
+impl Default for Rules {
+    /// Notifies about units that failed, have an erroneous or unknown state, or recovered from a failure.
+    fn default() -> Self {
+        let glob = |glob: &str| Some(Pattern::Glob(glob.to_string()));
+        Self(vec![
+            Rule {
+                new_load_state: glob("error"),
+                ..Rule::new(Action::Notify)
+            },
+            // only notify if not-found is coupled with anything not inactive
+            Rule {
+                new_load_state: glob("not-found"),
+                new_active_state: glob("inactive"),
+                ..Rule::new(Action::Ignore)
+            },
+            Rule {
+                new_load_state: glob("not-found"),
+                ..Rule::new(Action::Notify)
+            },
+            Rule {
+                new_load_state: glob("unknown:*"),
+                ..Rule::new(Action::Notify)
+            },
+            Rule {
+                new_active_state: glob("failed"),
+                ..Rule::new(Action::Notify)
+            },
+            Rule {
+                new_active_state: glob("unknown:*"),
+                ..Rule::new(Action::Notify)
+            },
+            // also notify when a unit recovered, as failed units are already matched above
+            Rule {
+                old_active_state: glob("failed"),
+                ..Rule::new(Action::Notify)
+            },
+        ])
+    }
+}
+
+impl Rules {
+    /// Evaluates the rules in order until a rule notifies about or ignores the change.
+    /// Changes that match no such rule are ignored.
+    pub fn evaluate(&self, change: &ChangedUnitStatus) -> Decision {
+        let mut severity = None;
+        for rule in self.0.iter().filter(|rule| rule.matches(change)) {
+            match rule.action {
+                Action::Notify => {
+                    return Decision {
+                        notify: true,
+                        severity,
+                    }
+                }
+                Action::Ignore => {
+                    return Decision {
+                        notify: false,
+                        severity,
+                    }
+                }
+                Action::Severity(assigned) => severity = Some(assigned),
+            }
+        }
+        Decision {
+            notify: false,
+            severity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dbus_systemd::dbus::UnitStatusRaw, filter::FilterState, state::tests::raw_unit_status,
+    };
+
+    fn status(name: &str, load_state: &str, active_state: &str, sub_state: &str) -> UnitStatus {
+        UnitStatus::from(UnitStatusRaw {
+            load_state: String::from(load_state),
+            sub_state: String::from(sub_state),
+            ..raw_unit_status(name, active_state)
+        })
+    }
+
+    fn change(old: Option<(&str, &str)>, new: (&str, &str)) -> ChangedUnitStatus {
+        ChangedUnitStatus {
+            old: old.map(|(load, active)| status("test.service", load, active, "test")),
+            new: status("test.service", new.0, new.1, "test"),
+        }
+    }
+
+    fn glob(glob: &str) -> Option<Pattern> {
+        Some(Pattern::Glob(glob.to_string()))
+    }
+
+    fn notifies(rules: &Rules, change: &ChangedUnitStatus) -> bool {
+        rules.evaluate(change).notify
+    }
+
+    #[test]
+    fn default_rules_keep_previous_behaviour() {
+        let rules = Rules::default();
+        let cases = [
+            (None, ("loaded", "failed"), true),
+            (None, ("loaded", "active"), false),
+            (Some(("loaded", "active")), ("loaded", "failed"), true),
+            (Some(("loaded", "failed")), ("loaded", "failed"), true),
+            (Some(("loaded", "failed")), ("loaded", "active"), true),
+            (Some(("loaded", "failed")), ("loaded", "activating"), true),
+            (Some(("loaded", "active")), ("loaded", "inactive"), false),
+            (Some(("loaded", "active")), ("loaded", "something"), true),
+            (Some(("loaded", "active")), ("error", "inactive"), true),
+            (Some(("loaded", "active")), ("not-found", "inactive"), false),
+            (Some(("loaded", "active")), ("not-found", "active"), true),
+            (Some(("loaded", "active")), ("something", "active"), true),
+            (Some(("loaded", "active")), ("masked", "inactive"), false),
+        ];
+        for (old, new, expected) in cases {
+            assert_eq!(
+                notifies(&rules, &change(old, new)),
+                expected,
+                "{:?} -> {:?}",
+                old,
+                new
+            );
+        }
+    }
+
+    #[test]
+    fn ignored_units_are_not_reminded_or_escalated() {
+        let rules = Rules(vec![
+            Rule {
+                unit: glob("ignored.service"),
+                ..Rule::new(Action::Ignore)
+            },
+            Rule {
+                new_active_state: glob("failed"),
+                ..Rule::new(Action::Notify)
+            },
+        ]);
+        let mut filter = FilterState::new().with_rules(rules);
+        let failed = |name: &str| status(name, "loaded", "failed", "failed");
+        assert!(filter.is_notified(&failed("test.service"), 0));
+        assert!(!filter.is_notified(&failed("ignored.service"), 0));
+        assert!(!filter.is_notified(&status("test.service", "loaded", "active", "running"), 0));
+    }
+
+    #[test]
+    fn unit_rule_matches_unit_name() {
+        let rules = Rules(vec![Rule {
+            unit: Some(Pattern::parse("re:test\\..*").unwrap()),
+            ..Rule::new(Action::Notify)
+        }]);
+        assert!(notifies(&rules, &change(None, ("loaded", "active"))));
+        let mut other = change(None, ("loaded", "active"));
+        other.new = status("other.service", "loaded", "active", "test");
+        assert!(!notifies(&rules, &other));
+    }
+
+    #[test]
+    fn old_state_rules_do_not_match_first_status() {
+        let rules = Rules(vec![Rule {
+            old_load_state: glob("loaded"),
+            old_active_state: glob("active"),
+            ..Rule::new(Action::Notify)
+        }]);
+        assert!(notifies(
+            &rules,
+            &change(Some(("loaded", "active")), ("loaded", "failed"))
+        ));
+        assert!(!notifies(
+            &rules,
+            &change(Some(("loaded", "inactive")), ("loaded", "failed"))
+        ));
+        assert!(!notifies(&rules, &change(None, ("loaded", "failed"))));
+    }
+
+    #[test]
+    fn sub_state_rules_match_sub_states() {
+        let rules = Rules(vec![Rule {
+            old_sub_state: glob("running"),
+            new_sub_state: glob("dead"),
+            ..Rule::new(Action::Notify)
+        }]);
+        let mut change = change(Some(("loaded", "active")), ("loaded", "inactive"));
+        assert!(!notifies(&rules, &change));
+        change.old = Some(status("test.service", "loaded", "active", "running"));
+        change.new = status("test.service", "loaded", "inactive", "dead");
+        assert!(notifies(&rules, &change));
+    }
+
+    #[test]
+    fn first_terminating_rule_wins() {
+        let rules = Rules(vec![
+            Rule {
+                new_active_state: glob("failed"),
+                ..Rule::new(Action::Ignore)
+            },
+            Rule::new(Action::Notify),
+        ]);
+        assert!(!notifies(&rules, &change(None, ("loaded", "failed"))));
+        assert!(notifies(&rules, &change(None, ("loaded", "active"))));
+        assert!(!notifies(
+            &Rules(vec![]),
+            &change(None, ("loaded", "failed"))
+        ));
+    }
+
+    #[test]
+    fn severity_rules_assign_severity_and_continue() {
+        let rules = Rules(vec![
+            Rule::new(Action::Severity(Severity::Info)),
+            Rule {
+                new_active_state: glob("failed"),
+                ..Rule::new(Action::Severity(Severity::Critical))
+            },
+            Rule::new(Action::Notify),
+        ]);
+        assert_eq!(
+            rules.evaluate(&change(None, ("loaded", "failed"))),
+            Decision {
+                notify: true,
+                severity: Some(Severity::Critical)
+            }
+        );
+        assert_eq!(
+            rules.evaluate(&change(None, ("loaded", "active"))),
+            Decision {
+                notify: true,
+                severity: Some(Severity::Info)
+            }
+        );
+    }
+}
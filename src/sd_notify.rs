@@ -0,0 +1,161 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process, time,
+};
+
+use anyhow::{Context, Result};
+
+/// Sends state changes of the service to systemd (see sd_notify(3)), e.g. for services of `Type=notify`
+/// and the watchdog (see `WatchdogSec=` of systemd.service).
+/// If the service manager did not request notifications, all messages are dropped silently.
+#[derive(Default)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// The interval for keep-alive messages, if the watchdog is enabled for this process.
+    watchdog_interval: Option<time::Duration>,
+}
+
+impl Notifier {
+    /// Creates a notifier for the socket and watchdog settings of the environment variables
+    /// `NOTIFY_SOCKET`, `WATCHDOG_USEC` and `WATCHDOG_PID` as set by systemd.
+    pub fn from_env() -> Result<Self> {
+        let socket_path = env::var("NOTIFY_SOCKET").ok();
+        let watchdog_usec = match env::var("WATCHDOG_PID") {
+            Ok(pid) if pid != process::id().to_string() => None,
+            _ => env::var("WATCHDOG_USEC").ok(),
+        };
+        Self::new(socket_path.as_deref(), watchdog_usec.as_deref())
+    }
+
+    fn new(socket_path: Option<&str>, watchdog_usec: Option<&str>) -> Result<Self> {
+        let socket_path = match socket_path {
+            Some(socket_path) if !socket_path.is_empty() => socket_path,
+            _ => return Ok(Self::default()),
+        };
+        let address = match socket_path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(socket_path),
+        }
+        .context(format!("invalid notification socket {}", socket_path))?;
+        let socket = UnixDatagram::unbound().context("could not create notification socket")?;
+        let watchdog_interval = match watchdog_usec {
+            Some(usec) => {
+                let usec: u64 = usec
+                    .parse()
+                    .context(format!("invalid watchdog timeout {}", usec))?;
+                // keep-alive messages are sent at half of the timeout, as recommended by sd_watchdog_enabled(3)
+                Some(time::Duration::from_micros(usec) / 2).filter(|interval| !interval.is_zero())
+            }
+            None => None,
+        };
+        Ok(Self {
+            socket: Some((socket, address)),
+            watchdog_interval,
+        })
+    }
+
+    /// Returns the interval in which [`Notifier::watchdog`] must be called, if the watchdog is enabled.
+    pub fn watchdog_interval(&self) -> Option<time::Duration> {
+        self.watchdog_interval
+    }
+
+    /// Tells systemd that the start-up is finished.
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    /// Tells systemd that the service is reloading its configuration.
+    /// The reload must be finished with [`Notifier::ready`].
+    pub fn reloading(&self) {
+        self.send(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+    }
+
+    /// Tells systemd that the service is shutting down.
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Sets the free-form status of the service, e.g. as shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={}", status));
+    }
+
+    /// Tells the watchdog that the service is still alive.
+    pub fn watchdog(&self) {
+        if self.watchdog_interval.is_some() {
+            self.send("WATCHDOG=1");
+        }
+    }
+
+    /// Sends the message to systemd.
+    /// Failures are only printed, as the service must continue to work without the service manager.
+    fn send(&self, message: &str) {
+        if let Some((socket, address)) = &self.socket {
+            if let Err(error) = socket.send_to_addr(message.as_bytes(), address) {
+                eprintln!("could not send '{}' to systemd: {}", message, error);
+            }
+        }
+    }
+}
+
+/// Returns the current time of `CLOCK_MONOTONIC` in microseconds, as required by systemd for reloads.
+fn monotonic_usec() -> u64 {
+    // SAFETY: an all-zero `timespec` is a valid value and `clock_gettime` only writes to it
+    let mut timespec: libc::timespec = unsafe { std::mem::zeroed() };
+    // SAFETY: the pointer is valid for the duration of the call
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut timespec) };
+    timespec.tv_sec as u64 * 1_000_000 + timespec.tv_nsec as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::temp_file_path;
+
+    #[test]
+    fn messages_are_sent_to_notification_socket() {
+        let path = temp_file_path();
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(path.to_str(), Some("3000000")).unwrap();
+        assert_eq!(
+            notifier.watchdog_interval(),
+            Some(time::Duration::from_millis(1_500))
+        );
+
+        notifier.ready();
+        notifier.status("monitoring 2 units, 1 failed");
+        notifier.watchdog();
+        notifier.reloading();
+        let mut buffer = [0; 64];
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let length = receiver.recv(&mut buffer).unwrap();
+            received.push(String::from_utf8_lossy(&buffer[..length]).to_string());
+        }
+        assert_eq!(
+            received[..3],
+            [
+                "READY=1",
+                "STATUS=monitoring 2 units, 1 failed",
+                "WATCHDOG=1"
+            ]
+        );
+        assert!(received[3].starts_with("RELOADING=1\nMONOTONIC_USEC="));
+
+        // without the socket, nothing is sent and the watchdog is disabled
+        let notifier = Notifier::new(None, Some("3000000")).unwrap();
+        assert_eq!(notifier.watchdog_interval(), None);
+        notifier.ready();
+        assert!(Notifier::new(path.to_str(), Some("invalid")).is_err());
+    }
+}
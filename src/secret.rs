@@ -0,0 +1,109 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// A value that must not be leaked, e.g. a webhook URL that contains a token.
+/// It is redacted in all `Debug` and `Display` output, so that it can not end up in logs or error messages.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// Reads the secret from the file at the given path.
+    /// A trailing newline is not part of the secret.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context(format!("could not read secret from file {:?}", path))?;
+        Ok(Self(
+            content.trim_end_matches(&['\r', '\n'][..]).to_string(),
+        ))
+    }
+
+    /// Reads the systemd credential with the given name (see `LoadCredential=` of systemd.exec),
+    /// if the credentials directory is set and contains it.
+    pub fn from_credential(name: &str) -> Result<Option<Self>> {
+        Self::from_credentials_directory(
+            env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from),
+            name,
+        )
+    }
+
+    fn from_credentials_directory(directory: Option<PathBuf>, name: &str) -> Result<Option<Self>> {
+        match directory.map(|directory| directory.join(name)) {
+            Some(path) if path.is_file() => Self::from_file(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the actual value of the secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([redacted])")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::temp_file_path;
+
+    #[test]
+    fn secret_is_redacted() {
+        let secret = Secret::new("https://discord.com/api/webhooks/1/token".to_string());
+        assert!(!format!("{:?}", secret).contains("token"));
+        assert!(!format!("{}", secret).contains("token"));
+        assert_eq!(secret.expose(), "https://discord.com/api/webhooks/1/token");
+    }
+
+    #[test]
+    fn secret_is_read_from_file_and_credentials_directory() {
+        let directory = temp_file_path();
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("discord-webhook-url"),
+            "https://example.com\n",
+        )
+        .unwrap();
+
+        let secret = Secret::from_file(&directory.join("discord-webhook-url")).unwrap();
+        assert_eq!(secret.expose(), "https://example.com");
+        assert_eq!(
+            Secret::from_credentials_directory(Some(directory.clone()), "discord-webhook-url")
+                .unwrap(),
+            Some(secret)
+        );
+        assert_eq!(
+            Secret::from_credentials_directory(Some(directory), "other-webhook-url").unwrap(),
+            None
+        );
+        assert_eq!(
+            Secret::from_credentials_directory(None, "discord-webhook-url").unwrap(),
+            None
+        );
+    }
+}
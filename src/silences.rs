@@ -0,0 +1,302 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{pattern::glob_matches, status::UnitStatus};
+
+/// Suppresses notifications about matching units for a period of time, e.g. during planned maintenance.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Silence {
+    pub id: u64,
+    /// A glob pattern for the names of the silenced units, e.g. `postgresql*.service`.
+    pub units: String,
+    /// If set, the silence only applies on the host with this name.
+    pub host: Option<String>,
+    /// Unix timestamp (in seconds) of the begin of the silence.
+    pub starts_at: u64,
+    /// Unix timestamp (in seconds) of the end of the silence.
+    pub ends_at: u64,
+    pub comment: String,
+    /// The changes that were suppressed by this silence.
+    #[serde(default)]
+    pub suppressed: Vec<SuppressedChanges>,
+}
+
+/// The changes of a single unit that were suppressed by a silence.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SuppressedChanges {
+    /// The latest suppressed status of the unit.
+    pub status: UnitStatus,
+    /// The number of suppressed changes.
+    pub count: usize,
+}
+
+impl Silence {
+    /// Returns true, if the silence is active at the given time and matches the unit on the given host.
+    fn matches(&self, unit: &str, hostname: &str, now: u64) -> bool {
+        self.starts_at <= now
+            && now < self.ends_at
+            && self.host.as_ref().is_none_or(|host| host == hostname)
+            && glob_matches(&self.units, unit)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SilencesFile {
+    next_id: u64,
+    silences: Vec<Silence>,
+}
+
+/// All silences, which are stored on disk if a file path is set.
+#[derive(Default)]
+pub struct Silences {
+    file_path: Option<PathBuf>,
+    hostname: String,
+    next_id: u64,
+    silences: Vec<Silence>,
+}
+
+impl Silences {
+    /// Creates the silences that are stored at the given path.
+    /// Any silences that were previously stored at this path are loaded.
+    pub fn new(file_path: PathBuf) -> Self {
+        let file: SilencesFile = fs::read(&file_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            file_path: Some(file_path),
+            hostname: gethostname::gethostname()
+                .into_string()
+                .unwrap_or_else(|_| "".to_string()),
+            next_id: file.next_id,
+            silences: file.silences,
+        }
+    }
+
+    /// Returns the path of the silences file that belongs to the given state file path,
+    /// i.e. a file next to the state file.
+    pub fn file_path_for_state_file(state_file_path: &Path) -> PathBuf {
+        state_file_path.with_extension("silences.json")
+    }
+
+    /// Returns all silences that did not expire yet.
+    pub fn list(&self) -> &[Silence] {
+        &self.silences
+    }
+
+    /// Adds a new silence and returns it.
+    pub fn add(
+        &mut self,
+        units: String,
+        host: Option<String>,
+        starts_at: u64,
+        ends_at: u64,
+        comment: String,
+    ) -> Result<Silence> {
+        if ends_at <= starts_at {
+            return Err(anyhow!("the end of a silence must be after its start"));
+        }
+        let silence = Silence {
+            id: self.next_id,
+            units,
+            host,
+            starts_at,
+            ends_at,
+            comment,
+            suppressed: Vec::new(),
+        };
+        self.next_id += 1;
+        self.silences.push(silence.clone());
+        self.save()?;
+        Ok(silence)
+    }
+
+    /// Ends the silence with the given ID at the given time and returns it.
+    /// The silence is removed by the next call of [`Silences::expire`], so that its suppressed changes are
+    /// reported like those of a silence that ended as planned.
+    pub fn end(&mut self, id: u64, now: u64) -> Result<Silence> {
+        let silence = self
+            .silences
+            .iter_mut()
+            .find(|silence| silence.id == id)
+            .ok_or_else(|| anyhow!("there is no silence with ID {}", id))?;
+        silence.starts_at = silence.starts_at.min(now);
+        silence.ends_at = silence.ends_at.min(now);
+        let silence = silence.clone();
+        self.save()?;
+        Ok(silence)
+    }
+
+    /// Returns true, if an active silence matches the unit.
+    pub fn is_silenced(&self, unit: &str, now: u64) -> bool {
+        self.silences
+            .iter()
+            .any(|silence| silence.matches(unit, &self.hostname, now))
+    }
+
+    /// Returns true, if an active silence matches the unit of the status.
+    /// The status is then recorded as suppressed by the first matching silence.
+    pub fn suppress(&mut self, status: &UnitStatus, now: u64) -> Result<bool> {
+        let hostname = &self.hostname;
+        let silence = self
+            .silences
+            .iter_mut()
+            .find(|silence| silence.matches(status.name(), hostname, now));
+        let silence = match silence {
+            Some(silence) => silence,
+            None => return Ok(false),
+        };
+        match silence
+            .suppressed
+            .iter_mut()
+            .find(|suppressed| suppressed.status.name() == status.name())
+        {
+            Some(suppressed) => {
+                suppressed.status = status.clone();
+                suppressed.count += 1;
+            }
+            None => silence.suppressed.push(SuppressedChanges {
+                status: status.clone(),
+                count: 1,
+            }),
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Removes all silences that ended before the given time and returns them.
+    pub fn expire(&mut self, now: u64) -> Result<Vec<Silence>> {
+        let (expired, silences) = self
+            .silences
+            .drain(..)
+            .partition(|silence| silence.ends_at <= now);
+        self.silences = silences;
+        if !expired.is_empty() {
+            self.save()?;
+        }
+        Ok(expired)
+    }
+
+    /// Writes the silences to disk, if a file path is set.
+    fn save(&self) -> Result<()> {
+        let file_path = match &self.file_path {
+            Some(file_path) => file_path,
+            None => return Ok(()),
+        };
+        let serialized = serde_json::to_string(&SilencesFile {
+            next_id: self.next_id,
+            silences: self.silences.clone(),
+        })
+        .context("could not serialize silences")?;
+        if let Some(dir_path) = file_path.parent() {
+            fs::create_dir_all(dir_path).context(format!(
+                "could not create directories for the silences file ({:?})",
+                dir_path
+            ))?;
+        }
+        fs::write(file_path, serialized).context(format!(
+            "could not write silences to file ({:?})",
+            file_path
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{temp_file_path, unit_status};
+
+    #[test]
+    fn silence_suppresses_matching_units_while_active() {
+        let mut silences = Silences::new(temp_file_path());
+        silences
+            .add("db-*.service".to_string(), None, 100, 200, "".to_string())
+            .unwrap();
+        assert!(!silences
+            .suppress(&unit_status("db-1.service", "failed"), 99)
+            .unwrap());
+        assert!(silences
+            .suppress(&unit_status("db-1.service", "failed"), 100)
+            .unwrap());
+        assert!(!silences
+            .suppress(&unit_status("web.service", "failed"), 100)
+            .unwrap());
+        assert!(!silences
+            .suppress(&unit_status("db-1.service", "failed"), 200)
+            .unwrap());
+    }
+
+    #[test]
+    fn silence_for_other_host_does_not_match() {
+        let mut silences = Silences::new(temp_file_path());
+        silences
+            .add(
+                "*".to_string(),
+                Some("some-other-host-name".to_string()),
+                0,
+                200,
+                "".to_string(),
+            )
+            .unwrap();
+        assert!(!silences.is_silenced("test.service", 100));
+    }
+
+    #[test]
+    fn expired_silence_contains_suppressed_changes() {
+        let path = temp_file_path();
+        let mut silences = Silences::new(path.clone());
+        silences
+            .add("*".to_string(), None, 0, 200, "maintenance".to_string())
+            .unwrap();
+        silences
+            .suppress(&unit_status("test.service", "failed"), 100)
+            .unwrap();
+        silences
+            .suppress(&unit_status("test.service", "active"), 101)
+            .unwrap();
+
+        // silences survive a restart
+        let mut silences = Silences::new(path);
+        assert_eq!(silences.expire(199).unwrap(), vec![]);
+        let expired = silences.expire(200).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(
+            expired[0].suppressed,
+            vec![SuppressedChanges {
+                status: unit_status("test.service", "active"),
+                count: 2
+            }]
+        );
+        assert!(silences.list().is_empty());
+    }
+
+    #[test]
+    fn ended_silence_expires_with_suppressed_changes() {
+        let mut silences = Silences::new(temp_file_path());
+        assert!(silences.end(0, 100).is_err());
+        let silence = silences
+            .add("*".to_string(), None, 0, 200, "".to_string())
+            .unwrap();
+        silences
+            .suppress(&unit_status("test.service", "failed"), 50)
+            .unwrap();
+
+        assert_eq!(silences.end(silence.id, 100).unwrap().ends_at, 100);
+        assert!(!silences.is_silenced("test.service", 100));
+        let expired = silences.expire(100).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].suppressed.len(), 1);
+        assert!(silences.list().is_empty());
+    }
+}
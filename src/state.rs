@@ -4,57 +4,198 @@ SPDX-FileCopyrightText: 2021 localthomas
 SPDX-License-Identifier: MIT OR Apache-2.0
 */
 
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::status::UnitStatus;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::status::{ActiveState, UnitStatus};
 
 pub trait SystemdState {
     /// Applies the new state and returns a list of changes compared to the existing state.
     /// Note that the changes are calculated based on the name of the unit.
-    /// Note that once a status for a unit name was saved, it is never "forgotten", even if a new state does not contain the unit name any more.
+    /// Note that once a status for a unit name was saved, it is not "forgotten" by a new state that does not contain
+    /// the unit name any more, as units are merged one by one, e.g. when recording a single unit.
+    /// Only [`SystemdState::forget_missing`] removes units, as it is given the complete current state of systemd.
     fn apply_new_status(&mut self, new_status: Vec<UnitStatus>) -> Vec<ChangedUnitStatus>;
+
+    /// Records the status of a single unit like [`SystemdState::apply_new_status`], but returns an error,
+    /// if the state can not be saved, e.g. when recorded by a CLI subcommand without access to the state file.
+    fn record(&mut self, status: UnitStatus) -> Result<()>;
+
+    /// Forgets the status and the failure of all units that are not contained in the complete current state
+    /// of systemd, e.g. after `systemctl reset-failed`, the removal of a transient unit or the deletion of a unit file.
+    /// Forgotten failures that were escalated are resolved like a recovery (see [`SystemdState::take_resolved_escalations`])
+    /// and the last known status of these units is returned.
+    fn forget_missing(&mut self, current_status: &[UnitStatus]) -> Vec<UnitStatus>;
+
+    /// Returns all units that are still failed and were not notified about within the given interval.
+    /// The returned failures are marked as notified at `now` (unix timestamp in seconds).
+    fn due_reminders(&mut self, interval: Duration, now: u64) -> Vec<(UnitStatus, Failure)>;
+
+    /// Returns all units that are failed for at least `delay`, were not yet escalated to the named provider
+    /// and for which `is_escalated` returns true, e.g. because they are not silenced.
+    /// Only the returned failures are marked as escalated to this provider, i.e. the others are escalated later,
+    /// once `is_escalated` returns true for them.
+    fn due_escalations(
+        &mut self,
+        provider: &str,
+        delay: Duration,
+        now: u64,
+        is_escalated: &mut dyn FnMut(&UnitStatus) -> bool,
+    ) -> Vec<(UnitStatus, Failure)>;
+
+    /// Returns and forgets the names of all providers the last failure of the unit was escalated to,
+    /// after the unit recovered from this failure.
+    fn take_resolved_escalations(&mut self, unit: &str) -> Vec<String>;
+
+    /// Marks the current failure of the unit as acknowledged, which suppresses reminders and escalations
+    /// until the unit recovers or fails anew.
+    /// Returns an error, if the unit is not failed.
+    fn acknowledge(&mut self, unit: &str) -> Result<()>;
+}
+
+/// Information about the ongoing failure of a unit.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Failure {
+    /// Unix timestamp (in seconds) of the time the unit was first seen in the failed state.
+    pub since: u64,
+    /// Unix timestamp (in seconds) of the last notification about this failure.
+    pub last_notified: u64,
+    /// The names of the notification providers this failure was escalated to.
+    #[serde(default)]
+    pub escalated: Vec<String>,
+    /// Set if someone is working on this failure, so that no more reminders and escalations are sent.
+    #[serde(default)]
+    pub acknowledged: bool,
+}
+
+/// The content of the state file.
+#[derive(Default, Serialize, Deserialize)]
+struct StateFile {
+    units: HashMap<String, UnitStatus>,
+    failures: HashMap<String, Failure>,
+    /// The providers that failures were escalated to, for units that recovered from these failures,
+    /// but whose recovery was not yet notified to these providers.
+    #[serde(default)]
+    resolved_escalations: HashMap<String, Vec<String>>,
+}
+
+impl StateFile {
+    /// Parses the state file, including the older format that only contained the units.
+    fn parse(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok().or_else(|| {
+            serde_json::from_slice(data).ok().map(|units| Self {
+                units,
+                failures: HashMap::new(),
+                resolved_escalations: HashMap::new(),
+            })
+        })
+    }
 }
 
 pub struct SystemdStateImpl {
     state_file_path: PathBuf,
     systemd_state: HashMap<String, UnitStatus>,
+    failures: HashMap<String, Failure>,
+    /// The providers that failures were escalated to, for units that recovered from these failures.
+    resolved_escalations: HashMap<String, Vec<String>>,
 }
 
 impl SystemdStateImpl {
     pub fn new(state_file_path: PathBuf) -> Self {
         // read state from disk
-        let systemd_state = {
-            if let Ok(data) = fs::read(&state_file_path) {
-                if let Ok(deserialized) = serde_json::from_slice(&data) {
-                    deserialized
-                } else {
-                    HashMap::new()
-                }
-            } else {
-                HashMap::new()
-            }
-        };
+        let state_file = fs::read(&state_file_path)
+            .ok()
+            .and_then(|data| StateFile::parse(&data))
+            .unwrap_or_default();
 
         Self {
             state_file_path,
-            systemd_state,
+            systemd_state: state_file.units,
+            failures: state_file.failures,
+            resolved_escalations: state_file.resolved_escalations,
         }
     }
-}
 
-/// Holds the information about a change between two states.
-/// Namely, the old and new states.
-#[derive(Debug, Eq, Hash, PartialEq, Clone)]
-pub struct ChangedUnitStatus {
-    pub old: Option<UnitStatus>,
-    pub new: UnitStatus,
-}
+    /// Returns all units that are failed according to the last known state, together with their failure.
+    pub fn failures(&self) -> Vec<(UnitStatus, Failure)> {
+        self.failures
+            .iter()
+            .filter_map(|(name, failure)| {
+                self.systemd_state
+                    .get(name)
+                    .map(|status| (status.clone(), failure.clone()))
+            })
+            .collect()
+    }
 
-impl<'a> SystemdState for SystemdStateImpl {
-    fn apply_new_status(&mut self, new_state: Vec<UnitStatus>) -> Vec<ChangedUnitStatus> {
+    /// Returns the last known status of the unit, if any.
+    pub fn status(&self, unit: &str) -> Option<&UnitStatus> {
+        self.systemd_state.get(unit)
+    }
+
+    /// Returns the failure of the unit, if it is failed according to the last known state.
+    pub fn failure(&self, unit: &str) -> Option<&Failure> {
+        self.failures.get(unit)
+    }
+
+    /// Writes the current state to disk.
+    fn save(&self) -> Result<()> {
+        let serialized_state = serde_json::to_string(&StateFile {
+            units: self.systemd_state.clone(),
+            failures: self.failures.clone(),
+            resolved_escalations: self.resolved_escalations.clone(),
+        })
+        .context("could not serialize systemd state")?;
+        if let Some(state_file_dir_path) = self.state_file_path.parent() {
+            fs::create_dir_all(state_file_dir_path).context(format!(
+                "could not create directories for the state file ({:?})",
+                state_file_dir_path
+            ))?;
+        }
+        fs::write(&self.state_file_path, serialized_state).context(format!(
+            "could not write systemd state to file ({:?})",
+            self.state_file_path
+        ))
+    }
+
+    /// Writes the current state to disk and panics, if it can not be written.
+    /// Used by the daemon, which can not continue without its state.
+    fn save_or_panic(&self) {
+        if let Err(error) = self.save() {
+            panic!("{:#}", error);
+        }
+    }
+
+    /// Merges the new status unit by unit into the current state without saving it and returns the changes.
+    fn merge_status(&mut self, new_state: Vec<UnitStatus>) -> Vec<ChangedUnitStatus> {
+        let now = unix_now();
         // apply the new state unit by unit and check for changes
         let mut changes: Vec<ChangedUnitStatus> = Vec::new();
         for new_status in new_state {
+            // track the begin of failures
+            if new_status.active_state() == &ActiveState::Failed {
+                self.failures
+                    .entry(new_status.name().clone())
+                    .or_insert(Failure {
+                        since: now,
+                        last_notified: now,
+                        escalated: Vec::new(),
+                        acknowledged: false,
+                    });
+            } else if let Some(failure) = self.failures.remove(new_status.name()) {
+                if !failure.escalated.is_empty() {
+                    self.resolved_escalations
+                        .insert(new_status.name().clone(), failure.escalated);
+                }
+            }
+
             // set the new one and get the old one
             let old_status = self
                 .systemd_state
@@ -77,31 +218,138 @@ impl<'a> SystemdState for SystemdStateImpl {
             }
         }
 
-        // save new state to disk
-        let serialized_state =
-            serde_json::to_string(&self.systemd_state).expect("could not serialize systemd state");
-        let state_file_dir_path = self
-            .state_file_path
-            .parent()
-            .expect("could not get parent dir of state_file_path");
-        fs::create_dir_all(state_file_dir_path).expect(&format!(
-            "could not create directories for the state file ({:?})",
-            state_file_dir_path
-        ));
-        fs::write(&self.state_file_path, serialized_state).expect(&format!(
-            "could not write systemd state to file ({:?})",
-            self.state_file_path
-        ));
+        changes
+    }
+}
+
+/// Holds the information about a change between two states.
+/// Namely, the old and new states.
+#[derive(Debug, Eq, Hash, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ChangedUnitStatus {
+    pub old: Option<UnitStatus>,
+    pub new: UnitStatus,
+}
 
+impl SystemdState for SystemdStateImpl {
+    fn apply_new_status(&mut self, new_state: Vec<UnitStatus>) -> Vec<ChangedUnitStatus> {
+        let changes = self.merge_status(new_state);
+        // save new state to disk
+        self.save_or_panic();
         changes
     }
+
+    fn record(&mut self, status: UnitStatus) -> Result<()> {
+        self.merge_status(vec![status]);
+        self.save()
+    }
+
+    fn forget_missing(&mut self, current_status: &[UnitStatus]) -> Vec<UnitStatus> {
+        let current: HashSet<&String> = current_status.iter().map(|status| status.name()).collect();
+        let units = self.systemd_state.len();
+        let failures = self.failures.len();
+        let mut resolved = Vec::new();
+        for (name, failure) in self.failures.iter_mut() {
+            if current.contains(name) || failure.escalated.is_empty() {
+                continue;
+            }
+            self.resolved_escalations
+                .insert(name.clone(), std::mem::take(&mut failure.escalated));
+            if let Some(status) = self.systemd_state.get(name) {
+                resolved.push(status.clone());
+            }
+        }
+        self.systemd_state.retain(|name, _| current.contains(name));
+        self.failures.retain(|name, _| current.contains(name));
+        if units != self.systemd_state.len() || failures != self.failures.len() {
+            self.save_or_panic();
+        }
+        resolved
+    }
+
+    fn due_reminders(&mut self, interval: Duration, now: u64) -> Vec<(UnitStatus, Failure)> {
+        let mut reminders = Vec::new();
+        for (name, failure) in self.failures.iter_mut() {
+            if failure.acknowledged
+                || now.saturating_sub(failure.last_notified) < interval.as_secs()
+            {
+                continue;
+            }
+            if let Some(status) = self.systemd_state.get(name) {
+                failure.last_notified = now;
+                reminders.push((status.clone(), failure.clone()));
+            }
+        }
+        if !reminders.is_empty() {
+            self.save_or_panic();
+        }
+        reminders
+    }
+
+    fn due_escalations(
+        &mut self,
+        provider: &str,
+        delay: Duration,
+        now: u64,
+        is_escalated: &mut dyn FnMut(&UnitStatus) -> bool,
+    ) -> Vec<(UnitStatus, Failure)> {
+        let mut escalations = Vec::new();
+        for (name, failure) in self.failures.iter_mut() {
+            if failure.acknowledged
+                || now.saturating_sub(failure.since) < delay.as_secs()
+                || failure
+                    .escalated
+                    .iter()
+                    .any(|escalated| escalated == provider)
+            {
+                continue;
+            }
+            if let Some(status) = self
+                .systemd_state
+                .get(name)
+                .filter(|status| is_escalated(status))
+            {
+                failure.escalated.push(provider.to_string());
+                escalations.push((status.clone(), failure.clone()));
+            }
+        }
+        if !escalations.is_empty() {
+            self.save_or_panic();
+        }
+        escalations
+    }
+
+    fn take_resolved_escalations(&mut self, unit: &str) -> Vec<String> {
+        let providers = self.resolved_escalations.remove(unit);
+        if providers.is_some() {
+            self.save_or_panic();
+        }
+        providers.unwrap_or_default()
+    }
+
+    fn acknowledge(&mut self, unit: &str) -> Result<()> {
+        let failure = self
+            .failures
+            .get_mut(unit)
+            .ok_or_else(|| anyhow!("unit {} is not failed", unit))?;
+        failure.acknowledged = true;
+        self.save()
+    }
+}
+
+/// Returns the current system time as seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::{dbus_systemd::dbus::UnitStatusRaw, silences::Silences};
 
-    fn temp_file_path() -> PathBuf {
+    pub fn temp_file_path() -> PathBuf {
         let mut dir = std::env::temp_dir();
         let file_name: String =
             rand::Rng::sample_iter(rand::thread_rng(), &rand::distributions::Alphanumeric)
@@ -112,6 +360,23 @@ pub mod tests {
         dir
     }
 
+    /// Returns the raw status of a loaded unit with the given name and active state.
+    pub fn raw_unit_status(name: &str, active_state: &str) -> UnitStatusRaw {
+        UnitStatusRaw {
+            name: String::from(name),
+            description: String::from("test"),
+            load_state: String::from("loaded"),
+            active_state: String::from(active_state),
+            sub_state: String::from("test"),
+            following_unit: String::from(""),
+        }
+    }
+
+    /// Returns the status of a loaded unit with the given name and active state.
+    pub fn unit_status(name: &str, active_state: &str) -> UnitStatus {
+        UnitStatus::from(raw_unit_status(name, active_state))
+    }
+
     pub struct MockupSystemdState {
         pub last_state: Option<Vec<UnitStatus>>,
     }
@@ -134,6 +399,41 @@ pub mod tests {
                 })
                 .collect()
         }
+
+        /// Replaces the last state with the status.
+        fn record(&mut self, status: UnitStatus) -> Result<()> {
+            self.last_state = Some(vec![status]);
+            Ok(())
+        }
+
+        /// Keeps the last state as is.
+        fn forget_missing(&mut self, _current_status: &[UnitStatus]) -> Vec<UnitStatus> {
+            Vec::new()
+        }
+
+        /// Never returns any reminders.
+        fn due_reminders(&mut self, _interval: Duration, _now: u64) -> Vec<(UnitStatus, Failure)> {
+            Vec::new()
+        }
+
+        /// Never returns any escalations.
+        fn due_escalations(
+            &mut self,
+            _provider: &str,
+            _delay: Duration,
+            _now: u64,
+            _is_escalated: &mut dyn FnMut(&UnitStatus) -> bool,
+        ) -> Vec<(UnitStatus, Failure)> {
+            Vec::new()
+        }
+
+        fn take_resolved_escalations(&mut self, _unit: &str) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn acknowledge(&mut self, _unit: &str) -> Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
@@ -240,4 +540,205 @@ pub mod tests {
             }]
         );
     }
+
+    #[test]
+    fn failures_are_tracked_and_reminded() {
+        let failed_status = unit_status("name", "failed");
+        let path = temp_file_path();
+        let mut state = SystemdStateImpl::new(path.clone());
+        state.apply_new_status(vec![failed_status.clone()]);
+        let since = state.failures["name"].since;
+        let interval = Duration::from_secs(60);
+
+        assert_eq!(state.due_reminders(interval, since + 59), vec![]);
+        assert_eq!(
+            state.due_reminders(interval, since + 60),
+            vec![(
+                failed_status.clone(),
+                Failure {
+                    since,
+                    last_notified: since + 60,
+                    escalated: vec![],
+                    acknowledged: false,
+                }
+            )]
+        );
+        assert_eq!(state.due_reminders(interval, since + 61), vec![]);
+
+        // the failure survives a restart
+        let mut state = SystemdStateImpl::new(path);
+        assert_eq!(state.due_reminders(interval, since + 120).len(), 1);
+
+        // a recovered unit is not reminded about anymore
+        let recovered_status = unit_status("name", "active");
+        state.apply_new_status(vec![recovered_status]);
+        assert_eq!(state.due_reminders(interval, since + 1000), vec![]);
+    }
+
+    #[test]
+    fn missing_failed_units_are_forgotten() {
+        let path = temp_file_path();
+        let mut state = SystemdStateImpl::new(path.clone());
+        let active_status = unit_status("active", "active");
+        state.apply_new_status(vec![unit_status("name", "failed"), active_status.clone()]);
+        let since = state.failures["name"].since;
+
+        // e.g. after systemctl reset-failed, the unit is not listed any more
+        state.forget_missing(std::slice::from_ref(&active_status));
+        assert_eq!(state.status("name"), None);
+        assert_eq!(state.failures(), vec![]);
+        assert_eq!(
+            state.due_reminders(Duration::from_secs(60), since + 1000),
+            vec![]
+        );
+        assert_eq!(
+            state.due_escalations("escalation", Duration::ZERO, since + 1000, &mut |_| true),
+            vec![]
+        );
+        assert_eq!(
+            SystemdStateImpl::new(path).status("active"),
+            Some(&active_status)
+        );
+    }
+
+    #[test]
+    fn escalations_of_forgotten_failures_are_resolved() {
+        let failed_status = unit_status("name", "failed");
+        let mut state = SystemdStateImpl::new(temp_file_path());
+        state.apply_new_status(vec![failed_status.clone()]);
+        let since = state.failures["name"].since;
+        assert_eq!(
+            state
+                .due_escalations("escalation", Duration::ZERO, since, &mut |_| true)
+                .len(),
+            1
+        );
+        state.apply_new_status(vec![unit_status("other", "failed")]);
+
+        // only the escalated failure is resolved
+        assert_eq!(state.forget_missing(&[]), vec![failed_status]);
+        assert_eq!(state.take_resolved_escalations("name"), vec!["escalation"]);
+        assert_eq!(
+            state.take_resolved_escalations("other"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn state_file_of_older_version_is_read() {
+        let path = temp_file_path();
+        fs::write(
+            &path,
+            r#"{"name":{"name":"name","description":"desc","load_state":"Loaded","active_state":"Active","sub_state":"running"}}"#,
+        )
+        .unwrap();
+        let state = SystemdStateImpl::new(path);
+        assert!(state.systemd_state.contains_key("name"));
+    }
+
+    #[test]
+    fn failure_during_silence_is_escalated_after_silence() {
+        let path = temp_file_path();
+        let mut state = SystemdStateImpl::new(path.clone());
+        state.apply_new_status(vec![unit_status("name", "failed")]);
+        let since = state.failures["name"].since;
+        let delay = Duration::from_secs(60);
+        let mut silences = Silences::new(Silences::file_path_for_state_file(&path));
+        silences
+            .add("name".to_string(), None, since, since + 120, "".to_string())
+            .unwrap();
+
+        let now = since + 60;
+        let mut is_escalated = |status: &UnitStatus| !silences.is_silenced(status.name(), now);
+        assert_eq!(
+            state.due_escalations("pager", delay, now, &mut is_escalated),
+            vec![]
+        );
+        assert!(state.failures["name"].escalated.is_empty());
+
+        let now = since + 120;
+        let mut is_escalated = |status: &UnitStatus| !silences.is_silenced(status.name(), now);
+        assert_eq!(
+            state
+                .due_escalations("pager", delay, now, &mut is_escalated)
+                .len(),
+            1
+        );
+        assert_eq!(state.failures["name"].escalated, vec!["pager"]);
+    }
+
+    #[test]
+    fn failures_are_escalated_once_per_provider() {
+        let failed_status = unit_status("name", "failed");
+        let recovered_status = unit_status("name", "active");
+        let path = temp_file_path();
+        let mut state = SystemdStateImpl::new(path.clone());
+        state.apply_new_status(vec![failed_status]);
+        let since = state.failures["name"].since;
+        let delay = Duration::from_secs(60);
+
+        assert_eq!(
+            state.due_escalations("pager", delay, since + 59, &mut |_| true),
+            vec![]
+        );
+        assert_eq!(
+            state
+                .due_escalations("pager", delay, since + 60, &mut |_| true)
+                .len(),
+            1
+        );
+        assert_eq!(
+            state.due_escalations("pager", delay, since + 61, &mut |_| true),
+            vec![]
+        );
+        assert_eq!(
+            state
+                .due_escalations("mail", delay, since + 61, &mut |_| true)
+                .len(),
+            1
+        );
+        assert_eq!(
+            state.take_resolved_escalations("name"),
+            Vec::<String>::new()
+        );
+
+        state.apply_new_status(vec![recovered_status]);
+        // the recovery is still sent to the escalation tiers after a restart
+        let mut state = SystemdStateImpl::new(path.clone());
+        assert_eq!(
+            state.take_resolved_escalations("name"),
+            vec!["pager".to_string(), "mail".to_string()]
+        );
+        assert_eq!(
+            state.take_resolved_escalations("name"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            SystemdStateImpl::new(path).take_resolved_escalations("name"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn acknowledged_failures_are_not_reminded_or_escalated() {
+        let failed_status = unit_status("name", "failed");
+        let recovered_status = unit_status("name", "active");
+        let mut state = SystemdStateImpl::new(temp_file_path());
+        assert!(state.acknowledge("name").is_err());
+
+        state.apply_new_status(vec![failed_status.clone()]);
+        let since = state.failures["name"].since;
+        state.acknowledge("name").unwrap();
+        let delay = Duration::from_secs(60);
+        assert_eq!(state.due_reminders(delay, since + 600), vec![]);
+        assert_eq!(
+            state.due_escalations("pager", delay, since + 600, &mut |_| true),
+            vec![]
+        );
+
+        // a new failure is not acknowledged
+        state.apply_new_status(vec![recovered_status]);
+        state.apply_new_status(vec![failed_status]);
+        assert!(!state.failures["name"].acknowledged);
+    }
 }
@@ -0,0 +1,293 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    control::format_timestamp,
+    dbus_systemd::{dbus::Connection, SystemdConnection},
+    outbox::Outbox,
+    silences::Silences,
+    state::{unix_now, SystemdStateImpl},
+    status::{ActiveState, UnitStatus},
+};
+
+/// A failed unit as known to the daemon.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct FailedUnit {
+    pub name: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    /// Unix timestamp (in seconds) of the time the unit was first seen in the failed state, if known.
+    pub failed_since: Option<u64>,
+    pub acknowledged: bool,
+    pub silenced: bool,
+    /// The outcome of the notifications about this unit.
+    pub notification: NotificationOutcome,
+}
+
+/// The state of the notifications about the current failure of a unit.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum NotificationOutcome {
+    /// Notifications about the failure were delivered to these providers.
+    Delivered { providers: Vec<String> },
+    /// Notifications about the unit are waiting in the outbox for these providers.
+    Pending {
+        providers: Vec<String>,
+        attempts: u32,
+        last_error: Option<String>,
+    },
+    /// Notifications about the failure were dropped for these providers, e.g. after exceeding the maximum age.
+    Dropped {
+        providers: Vec<String>,
+        last_error: Option<String>,
+    },
+    /// No notification about the failure was sent, e.g. as the rules ignore the change or no provider is routed.
+    NotNotified,
+}
+
+/// Prints the failed units of interest with their failure, acknowledgement, silence and notification state.
+/// The failed units are read from the state file or, if `live` is set, from systemd.
+pub fn run(config: &Config, live: bool, json: bool) -> Result<()> {
+    let state_file_path = Path::new(&config.state_file_path).to_path_buf();
+    let systemd = SystemdStateImpl::new(state_file_path.clone());
+    let silences = Silences::new(Silences::file_path_for_state_file(&state_file_path));
+    let outbox = Outbox::new(
+        Outbox::file_path_for_state_file(&state_file_path),
+        config.outbox_max_age,
+    );
+
+    let failed: Vec<UnitStatus> = if live {
+        let conn = Connection::new(config.dbus_timeout).context("could not create connection")?;
+        conn.list_units()
+            .context("could not list units")?
+            .into_iter()
+            .map(UnitStatus::from)
+            .filter(|status| status.active_state() == &ActiveState::Failed)
+            .collect()
+    } else {
+        systemd
+            .failures()
+            .into_iter()
+            .map(|(status, _)| status)
+            .collect()
+    };
+    let mut units: Vec<FailedUnit> = failed
+        .into_iter()
+        .filter(|status| config.unit_filter.matches(status.name()))
+        .map(|status| failed_unit(status, &systemd, &silences, &outbox, unix_now()))
+        .collect();
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&units).context("could not serialize status")?
+        );
+    } else if units.is_empty() {
+        println!("no failed units");
+    } else {
+        for unit in units {
+            println!("{}", format_unit(&unit));
+        }
+    }
+    Ok(())
+}
+
+fn failed_unit(
+    status: UnitStatus,
+    systemd: &SystemdStateImpl,
+    silences: &Silences,
+    outbox: &Outbox,
+    now: u64,
+) -> FailedUnit {
+    let failure = systemd.failure(status.name());
+    let entries = outbox.entries_for_unit(status.name());
+    let outcomes = outbox.outcomes_for_unit(
+        status.name(),
+        failure.map(|failure| failure.since).unwrap_or(0),
+    );
+    let (dropped, delivered): (Vec<_>, Vec<_>) = outcomes
+        .into_iter()
+        .partition(|outcome| outcome.error.is_some());
+    let notification = if !entries.is_empty() {
+        NotificationOutcome::Pending {
+            providers: entries.iter().map(|entry| entry.provider.clone()).collect(),
+            attempts: entries
+                .iter()
+                .map(|entry| entry.attempts)
+                .max()
+                .unwrap_or(0),
+            last_error: entries
+                .iter()
+                .rev()
+                .find_map(|entry| entry.last_error.clone()),
+        }
+    } else if !dropped.is_empty() {
+        NotificationOutcome::Dropped {
+            providers: dropped
+                .iter()
+                .map(|outcome| outcome.provider.clone())
+                .collect(),
+            last_error: dropped
+                .iter()
+                .rev()
+                .find_map(|outcome| outcome.error.clone()),
+        }
+    } else if !delivered.is_empty() {
+        NotificationOutcome::Delivered {
+            providers: delivered
+                .iter()
+                .map(|outcome| outcome.provider.clone())
+                .collect(),
+        }
+    } else {
+        NotificationOutcome::NotNotified
+    };
+    FailedUnit {
+        name: status.name().clone(),
+        load_state: status.load_state().to_string(),
+        active_state: status.active_state().to_string(),
+        sub_state: status.sub_state().clone(),
+        failed_since: failure.map(|failure| failure.since),
+        acknowledged: failure.is_some_and(|failure| failure.acknowledged),
+        silenced: silences.is_silenced(status.name(), now),
+        notification,
+    }
+}
+
+/// Formats the unit as a line with tab separated columns.
+fn format_unit(unit: &FailedUnit) -> String {
+    let notification = match &unit.notification {
+        NotificationOutcome::Delivered { providers } => {
+            format!("delivered to {}", providers.join(", "))
+        }
+        NotificationOutcome::Dropped {
+            providers,
+            last_error,
+        } => format!(
+            "dropped for {}{}",
+            providers.join(", "),
+            last_error
+                .as_ref()
+                .map(|error| format!(" ({})", error))
+                .unwrap_or_default()
+        ),
+        NotificationOutcome::NotNotified => "not notified".to_string(),
+        NotificationOutcome::Pending {
+            providers,
+            attempts,
+            last_error,
+        } => format!(
+            "pending for {} after {} attempts{}",
+            providers.join(", "),
+            attempts,
+            last_error
+                .as_ref()
+                .map(|error| format!(" ({})", error))
+                .unwrap_or_default()
+        ),
+    };
+    format!(
+        "{}\t{}/{}/{}\tfailed since {}\t{}\t{}\t{}",
+        unit.name,
+        unit.load_state,
+        unit.active_state,
+        unit.sub_state,
+        unit.failed_since
+            .map(format_timestamp)
+            .unwrap_or_else(|| "unknown".to_string()),
+        if unit.acknowledged {
+            "acknowledged"
+        } else {
+            "not acknowledged"
+        },
+        if unit.silenced {
+            "silenced"
+        } else {
+            "not silenced"
+        },
+        notification
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        notifications::{NotificationKind, UnitNotification},
+        outbox::PendingNotification,
+        state::{
+            tests::{temp_file_path, unit_status},
+            SystemdState,
+        },
+    };
+
+    #[test]
+    fn failed_unit_contains_failure_silence_and_outbox_state() {
+        let path = temp_file_path();
+        let mut systemd = SystemdStateImpl::new(path.clone());
+        systemd.apply_new_status(vec![
+            unit_status("a.service", "failed"),
+            unit_status("b.service", "active"),
+        ]);
+        systemd.acknowledge("a.service").unwrap();
+        let mut silences = Silences::new(Silences::file_path_for_state_file(&path));
+        silences
+            .add("a.*".to_string(), None, 0, u64::MAX, "".to_string())
+            .unwrap();
+        let mut outbox = Outbox::new(
+            Outbox::file_path_for_state_file(&path),
+            std::time::Duration::from_secs(60),
+        );
+        outbox
+            .push(
+                "discord",
+                PendingNotification::Units(vec![UnitNotification::new(
+                    unit_status("a.service", "failed"),
+                    NotificationKind::Changed,
+                )]),
+            )
+            .unwrap();
+
+        let failures = systemd.failures();
+        assert_eq!(failures.len(), 1);
+        let unit = failed_unit(failures[0].0.clone(), &systemd, &silences, &outbox, 1);
+        assert!(unit.failed_since.is_some());
+        assert!(unit.acknowledged);
+        assert!(unit.silenced);
+        assert_eq!(
+            unit.notification,
+            NotificationOutcome::Pending {
+                providers: vec!["discord".to_string()],
+                attempts: 0,
+                last_error: None
+            }
+        );
+        assert!(format_unit(&unit).starts_with("a.service\tloaded/failed/test\tfailed since "));
+
+        outbox.complete(0).unwrap();
+        let unit = failed_unit(failures[0].0.clone(), &systemd, &silences, &outbox, 1);
+        assert_eq!(
+            unit.notification,
+            NotificationOutcome::Delivered {
+                providers: vec!["discord".to_string()]
+            }
+        );
+        // a failure of a unit that was never sent to any provider
+        systemd.apply_new_status(vec![unit_status("b.service", "failed")]);
+        let status = unit_status("b.service", "failed");
+        let unit = failed_unit(status, &systemd, &silences, &outbox, 1);
+        assert_eq!(unit.notification, NotificationOutcome::NotNotified);
+        assert!(format_unit(&unit).ends_with("not notified"));
+    }
+}
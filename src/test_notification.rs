@@ -0,0 +1,214 @@
+/*
+SPDX-FileCopyrightText: 2021 localthomas
+
+SPDX-License-Identifier: MIT OR Apache-2.0
+*/
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::Config,
+    dbus_systemd::dbus::UnitStatusRaw,
+    filter::FilterState,
+    notifications::{self, NotificationKind, NotificationProvider, UnitNotification},
+    outbox::PendingNotification,
+    routing::Routes,
+    silences::Silences,
+    state::ChangedUnitStatus,
+    status::UnitStatus,
+};
+
+/// The states of the synthetic unit that is sent by the test notification.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TestUnit {
+    pub name: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    /// If set, the test notification is a change from this active state, e.g. `failed` for a recovery.
+    pub old_active_state: Option<String>,
+}
+
+impl TestUnit {
+    fn change(&self) -> ChangedUnitStatus {
+        let status = |active_state: &str| {
+            UnitStatus::from(UnitStatusRaw {
+                name: self.name.clone(),
+                description: "test notification".to_string(),
+                load_state: self.load_state.clone(),
+                active_state: active_state.to_string(),
+                sub_state: self.sub_state.clone(),
+                following_unit: String::new(),
+            })
+        };
+        ChangedUnitStatus {
+            old: self.old_active_state.as_deref().map(status),
+            new: status(&self.active_state),
+        }
+    }
+}
+
+/// Sends a notification about the synthetic unit as well as an error and a start notification
+/// to every configured notification provider and prints the result for each of them.
+/// The unit notification passes the unit filter, the rules and the routes like a real change,
+/// but silences are not applied.
+/// Returns an error, if any notification could not be sent.
+pub fn run(config: &Config, unit: &TestUnit) -> Result<()> {
+    let notifications = notifications::create_notifications(config)
+        .context("could not create notifications provider")?;
+    let mut filter = crate::create_filter(config, Silences::default());
+    let mut failed = false;
+    for (provider, kind, result) in send(&mut filter, &config.routes, &notifications, unit) {
+        match result {
+            Ok(true) => println!("provider '{}': {} notification: ok", provider, kind),
+            Ok(false) => println!(
+                "provider '{}': {} notification: not sent to this provider",
+                provider, kind
+            ),
+            Err(error) => {
+                failed = true;
+                println!(
+                    "provider '{}': {} notification: error: {:#}",
+                    provider, kind, error
+                );
+            }
+        }
+    }
+    if failed {
+        return Err(anyhow!("not all test notifications could be sent"));
+    }
+    Ok(())
+}
+
+/// Sends the test notifications synchronously and returns for each provider and kind of notification,
+/// whether it was sent, not sent as the daemon would not send it to the provider (e.g. due to the routes or
+/// an escalation tier), or failed.
+fn send(
+    filter: &mut FilterState,
+    routes: &Routes,
+    notifications: &[Box<dyn NotificationProvider>],
+    unit: &TestUnit,
+) -> Vec<(String, &'static str, Result<bool>)> {
+    let change = unit.change();
+    let unit_notification = if filter.filter_function(&change) {
+        let severity = filter.severity(&change);
+        Some(PendingNotification::Units(vec![UnitNotification::new(
+            change.new,
+            NotificationKind::Changed,
+        )
+        .with_severity(severity)]))
+    } else {
+        println!(
+            "unit notification: {} is ignored by the unit filter or the rules",
+            unit.name
+        );
+        None
+    };
+    let error = anyhow!("this is a test of the error notification");
+
+    // take the same path as the daemon to select the notifications for each provider
+    let execute = |provider: &dyn NotificationProvider, notification: &PendingNotification| {
+        match notification.for_provider(provider, routes) {
+            Some(notification) => notification.execute(provider)().map(|_| true),
+            None => Ok(false),
+        }
+    };
+    let mut results = Vec::new();
+    for provider in notifications {
+        let name = provider.name().to_string();
+        if let Some(notification) = &unit_notification {
+            results.push((
+                name.clone(),
+                "unit",
+                execute(provider.as_ref(), notification),
+            ));
+        }
+        results.push((
+            name.clone(),
+            "error",
+            provider.execute_error(&error)().map(|_| true),
+        ));
+        results.push((
+            name,
+            "start",
+            execute(provider.as_ref(), &PendingNotification::Start),
+        ));
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::tests::MockupNotificationProvider;
+
+    fn unit(active_state: &str) -> TestUnit {
+        TestUnit {
+            name: "test.service".to_string(),
+            load_state: "loaded".to_string(),
+            active_state: active_state.to_string(),
+            sub_state: "dead".to_string(),
+            old_active_state: None,
+        }
+    }
+
+    #[test]
+    fn test_notification_passes_filter_and_routes() {
+        let provider = MockupNotificationProvider::new();
+        let sent = provider.sent.clone();
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![Box::new(provider)];
+
+        let results = send(
+            &mut FilterState::new(),
+            &Routes::default(),
+            &notifications,
+            &unit("failed"),
+        );
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|(_, _, result)| *result.as_ref().unwrap()));
+        assert_eq!(sent.lock().unwrap()[0][0].status.name(), "test.service");
+
+        // an active unit without a previous failure is ignored by the default rules
+        let results = send(
+            &mut FilterState::new(),
+            &Routes::default(),
+            &notifications,
+            &unit("active"),
+        );
+        assert_eq!(results.len(), 2);
+
+        let routes = Routes::parse("other *").unwrap();
+        let results = send(
+            &mut FilterState::new(),
+            &routes,
+            &notifications,
+            &unit("failed"),
+        );
+        assert!(!results[0].2.as_ref().unwrap());
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn escalation_tier_does_not_receive_failure() {
+        let mut provider = MockupNotificationProvider::new();
+        provider.escalate_after = Some(std::time::Duration::from_secs(60));
+        let sent = provider.sent.clone();
+        let notifications: Vec<Box<dyn NotificationProvider>> = vec![Box::new(provider)];
+
+        let results = send(
+            &mut FilterState::new(),
+            &Routes::default(),
+            &notifications,
+            &unit("failed"),
+        );
+        let sent_kinds: Vec<&str> = results
+            .iter()
+            .filter(|(_, _, result)| *result.as_ref().unwrap())
+            .map(|(_, kind, _)| *kind)
+            .collect();
+        assert_eq!(sent_kinds, vec!["error"]);
+        assert!(sent.lock().unwrap().is_empty());
+    }
+}